@@ -0,0 +1,128 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, Result};
+
+use crate::{ioutils::{copy_archival_node, PositionWriter}, security::credentials::Credentials, structure::{header::HEADER_LENGTH, node::ChunkLocation, recovery::data_end, table::FileTable}};
+
+/// The size of an archive before and after it was compacted, without any
+/// recovery record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compaction {
+    pub before: u64,
+    pub after: u64
+}
+
+impl Compaction {
+    /// The number of bytes the compaction freed.
+    pub fn reclaimed(&self) -> u64 {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+/// Copies the live entries of the archive in `reader` into a new archive
+/// written to `writer`, leaving out the chunks of removed and replaced
+/// entries and the tables of earlier appends.
+///
+/// Chunks are bound to the archive id and the index of their entry, not
+/// to where they lie, so the header is kept and the chunks are copied
+/// without being decrypted. Any recovery record is left out,
+/// [Archive::compact](super::Archive::compact) rebuilds it. Legacy
+/// archives bind nothing and have no chunk index, so they cannot be
+/// compacted.
+pub fn compact<R: Read + Seek, W: Write + Seek>(reader: &mut R, writer: &mut W, credentials: &Credentials) -> Result<Compaction> {
+    let table = FileTable::from_reader(reader, credentials)?;
+    if table.header().is_legacy() {
+        return Err(anyhow!("Legacy archives cannot be compacted, extract and recreate the archive instead."));
+    }
+    let before = data_end(reader)?;
+
+    table.header().write(writer)?;
+    let mut compacted = FileTable::new(table.key().clone(), table.header().clone());
+    compacted.removed = table.removed.clone();
+    let mut chunks = PositionWriter::new(&mut *writer, HEADER_LENGTH);
+
+    for (index, old_position, node) in &table.map {
+        let mut node = node.clone();
+        let new_position = chunks.position();
+        if node.is_file() {
+            reader.seek(SeekFrom::Start(*old_position))?;
+            let offsets = copy_archival_node(reader, &mut chunks)?;
+            let matches_index = offsets.len() == node.chunks.len()
+                && node.chunks.iter().zip(&offsets).all(|(chunk, offset)| chunk.offset == old_position + offset);
+            if !matches_index {
+                return Err(anyhow!("The chunks of {:?} do not match its chunk index.", node.path));
+            }
+            for (chunk, offset) in node.chunks.iter_mut().zip(offsets) {
+                *chunk = ChunkLocation {
+                    offset: new_position + offset,
+                    ..*chunk
+                };
+            }
+        }
+        compacted.add(*index, new_position, node);
+    }
+
+    compacted.write(writer)?;
+    writer.flush()?;
+    Ok(Compaction {
+        before,
+        after: writer.stream_position()?
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{metadata, write}, io::Read, path::Path};
+
+    use anyhow::Result;
+
+    use crate::{archive::{Archive, ArchiveReader, Selection}, security::credentials::Credentials, structure::node::{ArchivalNode, NodeKind}, testing::{test_options, test_tree, TEST_PASSWORD}};
+
+    #[test]
+    pub fn test_remove_and_compact() -> Result<()> {
+        let source = test_tree(&[("notes.txt", &[b'a'; 100_000]), ("logs/old.log", &[b'o'; 50_000])])?;
+        let output = tempfile::tempdir()?;
+
+        let archive_path = output.path().join("archive.srs");
+        let mut writer = Archive::create(&archive_path, TEST_PASSWORD, test_options())?;
+        writer.add_path(source.path().join("notes.txt"))?;
+        writer.add_path(source.path().join("logs"))?;
+        writer.finish()?;
+
+        // Adding under an existing path replaces the entry.
+        write(source.path().join("notes.txt"), b"rewritten")?;
+        let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+        appender.add_path(source.path().join("notes.txt"))?;
+        assert!(appender.remove(&Selection::new(&["missing"], &[])?).is_err());
+        assert_eq!(appender.remove(&Selection::new(&["logs"], &[])?)?.len(), 2);
+        appender.finish()?;
+
+        let check = |reader: &mut ArchiveReader<_>| -> Result<()> {
+            assert_eq!(reader.files(), vec![Path::new("notes.txt")]);
+            let mut extracted = Vec::new();
+            reader.open_file("notes.txt")?.read_to_end(&mut extracted)?;
+            assert_eq!(extracted, b"rewritten");
+            Ok(())
+        };
+        check(&mut Archive::open(&archive_path, TEST_PASSWORD)?)?;
+
+        let credentials = Credentials::password(TEST_PASSWORD);
+        let compaction = Archive::compact(&archive_path, &credentials)?;
+        assert_eq!(compaction.after, metadata(&archive_path)?.len());
+        assert!(compaction.reclaimed() > 0);
+        check(&mut Archive::open(&archive_path, TEST_PASSWORD)?)?;
+
+        // Nothing is left to reclaim and the removed indexes are kept.
+        let compaction = Archive::compact(&archive_path, &credentials)?;
+        assert_eq!(compaction.reclaimed(), 0);
+        let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+        assert_eq!(appender.add_node(ArchivalNode {
+            path: "empty".into(),
+            kind: NodeKind::Directory,
+            ..Default::default()
+        })?, 4);
+        appender.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use anyhow::{anyhow, Result};
+use zeroize::Zeroizing;
+
+use crate::{security::{secret::SecretKey, secure::{read_sealed, ChunkContext}}, structure::{compression::Compression, node::{ArchivalNode, ChunkLocation}}};
+
+/// Reads the contents of a single file of an archive, decrypting only the
+/// chunks the requested range falls in.
+///
+/// The chunk holding any position is found in the chunk index of the
+/// file, without reading the chunks before it. Chunks are authenticated
+/// as they are read, a failure is returned as an [io::Error] wrapping the
+/// [ArchiveError](crate::error::ArchiveError).
+///
+/// Created by [ArchiveReader::open_file](super::ArchiveReader::open_file).
+pub struct EntryReader<'a, R: Read + Seek> {
+    reader: &'a mut R,
+    key: &'a SecretKey,
+    context: ChunkContext,
+    compression: Compression,
+    chunks: Vec<ChunkLocation>,
+    /// The position within the file each chunk starts at.
+    starts: Vec<u64>,
+    length: u64,
+    position: u64,
+    /// The most recently decrypted chunk and its index.
+    current: Option<(usize, Zeroizing<Vec<u8>>)>
+}
+
+impl<'a, R: Read + Seek> EntryReader<'a, R> {
+    /// Reads `node` through the chunk index recorded for it.
+    pub(crate) fn new(reader: &'a mut R, key: &'a SecretKey, context: ChunkContext, node: &ArchivalNode) -> Result<Self> {
+        if context == ChunkContext::Legacy {
+            return Err(anyhow!("Legacy archives do not support random access, extract the file instead."));
+        }
+        if node.chunks.is_empty() {
+            return Err(anyhow!("The file {:?} has no chunk index.", node.path));
+        }
+        let starts = node.chunks.iter()
+            .scan(0, |start, chunk| {
+                let current = *start;
+                *start += u64::from(chunk.length);
+                Some(current)
+            })
+            .collect();
+
+        Ok(Self {
+            reader,
+            key,
+            context,
+            compression: node.compression,
+            chunks: node.chunks.clone(),
+            starts,
+            length: node.size(),
+            position: 0,
+            current: None
+        })
+    }
+    /// The length of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+    /// Decrypts the `index`-th chunk, unless it is the one held already.
+    fn chunk(&mut self, index: usize) -> Result<&[u8]> {
+        if self.current.as_ref().map(|(current, _)| *current) != Some(index) {
+            let last = index + 1 == self.chunks.len();
+            let location = self.chunks[index];
+            self.reader.seek(SeekFrom::Start(location.offset))?;
+            let sealed = read_sealed(self.reader)?;
+            let opened = self.context.open_chunk(self.key, &sealed, index as u64, last)?;
+            let chunk = self.compression.decompress_chunk(Zeroizing::new(opened))?;
+            if chunk.len() != location.length as usize {
+                return Err(anyhow!("Chunk {index} of entry {} holds {} bytes but the index records {}.", self.context.file_index(), chunk.len(), location.length));
+            }
+            self.current = Some((index, chunk));
+        }
+        Ok(self.current.as_ref().map(|(_, chunk)| chunk.as_slice()).unwrap_or_default())
+    }
+}
+
+impl<R: Read + Seek> Read for EntryReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.length {
+            return Ok(0);
+        }
+        let index = self.starts.partition_point(|start| *start <= self.position) - 1;
+        let offset = (self.position - self.starts[index]) as usize;
+        let chunk = self.chunk(index).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+        let read = buf.len().min(chunk.len() - offset);
+        buf[..read].copy_from_slice(&chunk[offset..offset + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for EntryReader<'_, R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset)
+        };
+        self.position = position.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Seeking to a negative position."))?;
+        Ok(self.position)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{sink, Cursor, Read, Seek, SeekFrom};
+
+    use anyhow::Result;
+
+    use crate::{archive::{AddOptions, ArchiveReader, ArchiveWriter, EntryStatus}, constants::CHUNK_SIZE, error::ArchiveError, structure::{compression::{Compression, CompressionAlgorithm}, node::ArchivalNode, table::FileTable}, testing::{test_archive, test_header, test_options, test_tree, TEST_PASSWORD}};
+
+    #[test]
+    pub fn test_random_access() -> Result<()> {
+        let contents = (0..CHUNK_SIZE * 5 / 2).map(|i| (i / 7) as u8).collect::<Vec<u8>>();
+        let source = test_tree(&[("large", &contents)])?;
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), TEST_PASSWORD, test_options())?;
+        writer.add_path_with(source.path().join("large"), &AddOptions {
+            compression: Compression::with_default_level(CompressionAlgorithm::Zstd),
+            ..Default::default()
+        })?;
+        let archive = writer.finish()?.into_inner();
+
+        let mut reader = ArchiveReader::new(Cursor::new(archive), TEST_PASSWORD)?;
+        let mut entry = reader.open_file("large")?;
+        assert_eq!(entry.len(), contents.len() as u64);
+
+        // A range straddling the first two chunks.
+        let mut range = vec![0u8; 100];
+        entry.seek(SeekFrom::Start(CHUNK_SIZE as u64 - 50))?;
+        entry.read_exact(&mut range)?;
+        assert_eq!(range, contents[CHUNK_SIZE - 50..CHUNK_SIZE + 50]);
+
+        let mut tail = Vec::new();
+        entry.seek(SeekFrom::End(-10))?;
+        entry.read_to_end(&mut tail)?;
+        assert_eq!(tail, contents[contents.len() - 10..]);
+
+        let mut everything = Vec::new();
+        entry.rewind()?;
+        entry.read_to_end(&mut everything)?;
+        assert_eq!(everything, contents);
+
+        assert!(entry.seek(SeekFrom::Current(-1 - contents.len() as i64)).is_err());
+        assert!(reader.open_file("missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_index_must_match_chunks() -> Result<()> {
+        let source = test_tree(&[("file", &(0..CHUNK_SIZE * 2).map(|i| i as u8).collect::<Vec<u8>>())])?;
+
+        let (key, header) = test_header()?;
+        let mut archive = Cursor::new(Vec::new());
+        header.write(&mut archive)?;
+        let mut node = ArchivalNode { path: "file".into(), source: Some(source.path().join("file")), ..Default::default() };
+        let position = node.write(&mut archive, &key, &header.chunk_context(0))?;
+
+        // An index with the chunks swapped, and one with a wrong length.
+        let mut swapped = node.clone();
+        swapped.chunks.swap(0, 1);
+        let mut resized = node.clone();
+        resized.chunks[1].length -= 1;
+
+        for tampered in [swapped, resized] {
+            let mut archive = archive.clone();
+            let mut table = FileTable::new(key.clone(), header.clone());
+            table.add(0, position, tampered);
+            table.write(&mut archive)?;
+
+            let mut reader = ArchiveReader::new(Cursor::new(archive.into_inner()), TEST_PASSWORD)?;
+            let mut contents = Vec::new();
+            assert!(reader.open_file("file")?.read_to_end(&mut contents).is_err());
+            assert!(!reader.verify()?.is_ok());
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_rejects_oversized_chunk() -> Result<()> {
+        let mut archive = test_archive(&[("file", &[7u8; CHUNK_SIZE * 2])])?;
+        let chunk = ArchiveReader::new(Cursor::new(archive.clone()), TEST_PASSWORD)?.entries()[0].2.chunks[1];
+
+        // The length follows the nonce.
+        let length = chunk.offset as usize + 12;
+        archive[length..length + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut reader = ArchiveReader::new(Cursor::new(archive), TEST_PASSWORD)?;
+        let mut entry = reader.open_file("file")?;
+        entry.seek(SeekFrom::Start(CHUNK_SIZE as u64))?;
+        assert!(entry.read(&mut [0u8; 1]).is_err());
+
+        let error = reader.extract_entry(0, &mut sink()).unwrap_err();
+        assert_eq!(error.downcast_ref::<ArchiveError>(), Some(&ArchiveError::ChunkAuthentication { file_index: 0, chunk_index: 1 }));
+        assert_eq!(reader.verify()?.entries[0].status, EntryStatus::CorruptChunk { chunk_index: 1, offset: chunk.offset });
+        Ok(())
+    }
+}
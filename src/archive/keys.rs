@@ -0,0 +1,201 @@
+use std::{fs::{File, OpenOptions}, io::Write, path::Path};
+
+use anyhow::{anyhow, Result};
+
+use crate::{security::{credentials::Credentials, kdf::KdfParams, recipients::Recipient, secret::SecretKey, slots::KeySlot}, structure::{header::ArchiveHeader, trailer::Trailer}};
+
+/// Manages the key slots of an existing archive.
+///
+/// Every change rewrites a single slot in the header in place, along with
+/// the parity of the recovery record, and is synced to disk before
+/// returning. The archive key, and so the chunks
+/// and the table, are never touched.
+pub struct ArchiveKeys {
+    file: File,
+    header: ArchiveHeader,
+    key: SecretKey,
+    /// The slot the archive was unlocked with.
+    unlocked_slot: usize
+}
+
+impl ArchiveKeys {
+    /// Opens the archive at `path` for changing its key slots, unlocking
+    /// it with `password`.
+    pub fn open(path: impl AsRef<Path>, password: &str) -> Result<Self> {
+        Self::open_with(path, &Credentials::password(password))
+    }
+    /// Opens the archive at `path` for changing its key slots, unlocking
+    /// it with a password, identities or both.
+    pub fn open_with(path: impl AsRef<Path>, credentials: &Credentials) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let header = ArchiveHeader::read(&mut file)?;
+        if header.is_legacy() {
+            return Err(anyhow!("Legacy archives have a single password and no key slots."));
+        }
+        let (unlocked_slot, key) = header.unlock_slot(credentials)?;
+        // Make sure the unlocked key is the one the archive was written with.
+        Trailer::read(&mut file, &key, &header)?;
+
+        Ok(Self {
+            file,
+            header,
+            key,
+            unlocked_slot
+        })
+    }
+    /// The key slots of the archive, empty ones included.
+    pub fn slots(&self) -> &[KeySlot] {
+        &self.header.key_slots
+    }
+    /// The index of the key slot the archive was opened with.
+    pub fn unlocked_slot(&self) -> usize {
+        self.unlocked_slot
+    }
+    /// Replaces the password the archive was opened with by `password`,
+    /// returning the index of its new key slot.
+    ///
+    /// The new password is written to an empty slot and synced before
+    /// the old slot is cleared, so a crash at any point leaves at least
+    /// one of the two passwords able to open the archive.
+    pub fn change_password(&mut self, password: &str, kdf_params: &KdfParams) -> Result<usize> {
+        self.change_credentials(&Credentials::password(password), kdf_params)
+    }
+    /// Like [ArchiveKeys::change_password] but the new slot is wrapped
+    /// with the password and keyfile of `credentials`, whichever are
+    /// present.
+    pub fn change_credentials(&mut self, credentials: &Credentials, kdf_params: &KdfParams) -> Result<usize> {
+        if !matches!(self.header.key_slots[self.unlocked_slot], KeySlot::Password { .. }) {
+            return Err(anyhow!("The archive was not opened with a password or keyfile, add one instead."));
+        }
+        let index = self.add_credentials(credentials, kdf_params)?;
+
+        let old = self.unlocked_slot;
+        self.header.key_slots[old] = KeySlot::Empty;
+        self.write_slot(old)?;
+        self.unlocked_slot = index;
+        Ok(index)
+    }
+    /// Adds `password` in the first empty key slot, returning its index.
+    pub fn add_password(&mut self, password: &str, kdf_params: &KdfParams) -> Result<usize> {
+        self.add_credentials(&Credentials::password(password), kdf_params)
+    }
+    /// Adds a key slot wrapped with the password and keyfile of
+    /// `credentials`, whichever are present, returning its index.
+    pub fn add_credentials(&mut self, credentials: &Credentials, kdf_params: &KdfParams) -> Result<usize> {
+        let index = self.empty_slot()?;
+        self.header.key_slots[index] = KeySlot::wrap_password(&self.key, credentials, kdf_params, &self.header.archive_id)?;
+        self.write_slot(index)?;
+        Ok(index)
+    }
+    /// Adds `recipient` in the first empty key slot, returning its index.
+    pub fn add_recipient(&mut self, recipient: &Recipient) -> Result<usize> {
+        let index = self.empty_slot()?;
+        self.header.key_slots[index] = KeySlot::wrap_recipient(&self.key, recipient, &self.header.archive_id)?;
+        self.write_slot(index)?;
+        Ok(index)
+    }
+    /// Empties the `index`-th key slot, refusing to remove the last one.
+    pub fn remove_slot(&mut self, index: usize) -> Result<()> {
+        match self.header.key_slots.get(index) {
+            None => return Err(anyhow!("The archive has no key slot {index}.")),
+            Some(slot) if slot.is_empty() => return Err(anyhow!("Key slot {index} is already empty.")),
+            Some(_) => {}
+        }
+        if self.header.key_slots.iter().filter(|slot| !slot.is_empty()).count() == 1 {
+            return Err(anyhow!("Refusing to remove the last key slot, the archive could never be opened again."));
+        }
+
+        self.header.key_slots[index] = KeySlot::Empty;
+        self.write_slot(index)
+    }
+    fn empty_slot(&self) -> Result<usize> {
+        self.header.key_slots.iter().position(KeySlot::is_empty)
+            .ok_or_else(|| anyhow!("All {} key slots are in use, remove one first.", self.header.key_slots.len()))
+    }
+    fn write_slot(&mut self, index: usize) -> Result<()> {
+        self.header.write_slot(&mut self.file, index)?;
+        self.file.flush()?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{archive::Archive, structure::header::{ArchiveHeader, HEADER_LENGTH}, testing::{test_options, test_params}};
+
+    use super::ArchiveKeys;
+
+    #[test]
+    pub fn test_key_slots() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("archive.srs");
+        let params = test_params();
+        Archive::create(&path, "first", test_options())?.finish()?;
+
+        let mut keys = ArchiveKeys::open(&path, "first")?;
+        assert_eq!(keys.add_password("second", &params)?, 1);
+        assert!(ArchiveKeys::open(&path, "wrong").is_err());
+
+        Archive::open(&path, "second")?;
+        keys.remove_slot(0)?;
+        assert!(Archive::open(&path, "first").is_err());
+        Archive::open(&path, "second")?;
+
+        // The last slot cannot be removed.
+        assert!(keys.remove_slot(1).is_err());
+        assert_eq!(keys.slots().iter().filter(|slot| !slot.is_empty()).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_change_password() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("archive.srs");
+        let params = test_params();
+        Archive::create(&path, "old", test_options())?.finish()?;
+        let before = std::fs::read(&path)?;
+
+        let mut keys = ArchiveKeys::open(&path, "old")?;
+        assert_eq!(keys.change_password("new", &params)?, 1);
+        assert_eq!(keys.unlocked_slot(), 1);
+        assert!(keys.slots()[0].is_empty());
+
+        assert!(Archive::open(&path, "old").is_err());
+        Archive::open(&path, "new")?;
+
+        // Only the key slots were rewritten.
+        let after = std::fs::read(&path)?;
+        let slots = ArchiveHeader::slot_offset(0) as usize..HEADER_LENGTH as usize;
+        assert_eq!(before.len(), after.len());
+        assert_eq!(before[..slots.start], after[..slots.start]);
+        assert_eq!(before[slots.end..], after[slots.end..]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_change_password_keeps_record() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("archive.srs");
+        let params = test_params();
+        Archive::create(&path, "old", test_options())?.finish()?;
+        Archive::protect(&path, 10)?;
+
+        ArchiveKeys::open(&path, "old")?.change_password("new", &params)?;
+        assert!(Archive::repair(&path)?.is_empty());
+
+        // A damaged slot is rebuilt as the new password, not the old one.
+        let mut damaged = std::fs::read(&path)?;
+        damaged[ArchiveHeader::slot_offset(1) as usize + 40] ^= 0x01;
+        damaged[ArchiveHeader::slot_offset(0) as usize] ^= 0x01;
+        std::fs::write(&path, damaged)?;
+        assert_eq!(Archive::repair(&path)?.data_blocks, 1);
+        Archive::open(&path, "new")?;
+        assert!(Archive::open(&path, "old").is_err());
+        Ok(())
+    }
+}
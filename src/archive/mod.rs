@@ -0,0 +1,242 @@
+use std::{fs::{remove_file, rename, File, OpenOptions}, io::{BufReader, BufWriter}, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, Result};
+
+use crate::{security::{credentials::Credentials, kdf::KdfParams, recipients::Recipient}, structure::{compression::Compression, metadata::Ownership, recovery::{self, RecoveryRecord, Repair}}};
+
+pub mod compact;
+pub mod entry;
+pub mod keys;
+pub mod reader;
+pub mod select;
+pub mod verify;
+pub mod writer;
+
+pub use compact::Compaction;
+pub use entry::EntryReader;
+pub use keys::ArchiveKeys;
+pub use reader::ArchiveReader;
+pub use select::Selection;
+pub use verify::{EntryStatus, VerifyReport};
+pub use writer::ArchiveWriter;
+
+/// Options used when creating an archive.
+#[derive(Clone, Debug, Default)]
+pub struct CreateOptions {
+    /// The Argon2 parameters the password is stretched with. These are
+    /// recorded in its key slot.
+    pub kdf_params: KdfParams,
+    /// The X25519 recipients the archive key is also wrapped to, each in
+    /// a key slot of its own.
+    pub recipients: Vec<Recipient>
+}
+
+/// Options controlling how a source tree is added: the paths it is
+/// stored under, the equivalents of tar's `-C` and `--strip-components`,
+/// and the compression of its files.
+///
+/// By default a tree is stored under the name of its root, so adding
+/// `/home/me/project` stores `project/...`.
+#[derive(Clone, Debug, Default)]
+pub struct AddOptions {
+    /// The directory added paths are resolved against. When set, entries
+    /// are stored relative to it instead of under the name of their root.
+    pub base: Option<PathBuf>,
+    /// The number of leading components dropped from every stored path.
+    /// Entries left with no components are skipped.
+    pub strip_components: usize,
+    /// The path every stored path is placed beneath.
+    pub prefix: Option<PathBuf>,
+    /// How the contents of added files are compressed. Chunks that do
+    /// not shrink are stored uncompressed regardless.
+    pub compression: Compression,
+    /// The entries added, matched against their stored paths. Excluded
+    /// directories are not descended into, while directories that are
+    /// not included still are so that included entries beneath them are
+    /// found.
+    pub selection: Selection,
+    /// Whether entries matched by the `.gitignore` and `.ignore` files
+    /// found in added directories are skipped.
+    pub ignore_files: bool,
+    /// Files larger than this many bytes are skipped.
+    pub max_file_size: Option<u64>,
+    /// Whether to stay on the file system of the added path rather than
+    /// descending into others mounted beneath it.
+    pub one_file_system: bool,
+    /// Whether symbolic links are followed and their targets added in
+    /// their place, rather than being stored as links.
+    pub follow_symlinks: bool
+}
+
+/// Options used when extracting an archive.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Whether the recorded owner and group are restored.
+    pub ownership: Ownership,
+    /// The entries to extract, every entry by default.
+    pub selection: Selection
+}
+
+/// Entry point for creating and opening archives on disk.
+///
+/// Use [ArchiveWriter::new] and [ArchiveReader::new] directly to work
+/// with any other [Writer](std::io::Write) or [Reader](std::io::Read).
+pub struct Archive;
+
+impl Archive {
+    /// Creates a new archive at `path`, replacing any existing file.
+    ///
+    /// The archive itself is skipped when a directory it lies in is
+    /// added, as are the archives of the other constructors.
+    pub fn create(path: impl AsRef<Path>, password: &str, options: CreateOptions) -> Result<ArchiveWriter<BufWriter<File>>> {
+        Self::create_with(path, &Credentials::password(password), options)
+    }
+    /// Creates a new archive at `path` that only the identities of
+    /// `options.recipients` can open.
+    pub fn create_for_recipients(path: impl AsRef<Path>, options: CreateOptions) -> Result<ArchiveWriter<BufWriter<File>>> {
+        Self::create_with(path, &Credentials::default(), options)
+    }
+    /// Creates a new archive at `path` whose first key slot is wrapped
+    /// with the password and keyfile of `credentials`.
+    pub fn create_with(path: impl AsRef<Path>, credentials: &Credentials, options: CreateOptions) -> Result<ArchiveWriter<BufWriter<File>>> {
+        let file = File::create(path)?;
+        let metadata = file.metadata()?;
+        let mut writer = ArchiveWriter::with_credentials(BufWriter::new(file), credentials, options)?;
+        writer.skip_file(&metadata);
+        Ok(writer)
+    }
+    /// Opens the archive at `path` to add entries to it, see
+    /// [ArchiveWriter::append].
+    pub fn append(path: impl AsRef<Path>, password: &str) -> Result<ArchiveWriter<File>> {
+        Self::append_with(path, &Credentials::password(password))
+    }
+    /// Like [Archive::append] with a password, keyfile, identities or any
+    /// of them.
+    pub fn append_with(path: impl AsRef<Path>, credentials: &Credentials) -> Result<ArchiveWriter<File>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let metadata = file.metadata()?;
+        let mut writer = ArchiveWriter::append(file, credentials)?;
+        writer.skip_file(&metadata);
+        Ok(writer)
+    }
+    /// Rewrites the archive at `path` without the chunks of removed and
+    /// replaced entries, see [compact::compact].
+    ///
+    /// The compacted archive is written next to it and renamed over it
+    /// once complete, so an interruption leaves the original in place.
+    /// A recovery record is rebuilt at the same size.
+    pub fn compact(path: impl AsRef<Path>, credentials: &Credentials) -> Result<Compaction> {
+        let path = path.as_ref();
+        let name = path.file_name().ok_or_else(|| anyhow!("{path:?} does not name a file."))?;
+        let temporary = path.with_file_name(format!(".{}.compacting", name.to_string_lossy()));
+
+        match compact_into(path, &temporary, credentials) {
+            Ok(compaction) => {
+                rename(&temporary, path)?;
+                Ok(compaction)
+            }
+            Err(e) => {
+                let _ = remove_file(&temporary);
+                Err(e)
+            }
+        }
+    }
+    /// Writes a recovery record sized as `percent` of the archive at
+    /// `path` after its trailer, replacing any it already has, see
+    /// [recovery::protect].
+    pub fn protect(path: impl AsRef<Path>, percent: u8) -> Result<RecoveryRecord> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let record = recovery::protect(&mut file, percent)?;
+        file.sync_all()?;
+        Ok(record)
+    }
+    /// Rebuilds the damaged parts of the archive at `path` in place from
+    /// its recovery record, see [recovery::repair]. No key is needed.
+    pub fn repair(path: impl AsRef<Path>) -> Result<Repair> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let repair = recovery::repair(&mut file)?;
+        file.sync_all()?;
+        Ok(repair)
+    }
+    /// The recovery record of the archive at `path`, if it has one.
+    pub fn recovery_record(path: impl AsRef<Path>) -> Result<Option<RecoveryRecord>> {
+        Ok(RecoveryRecord::read(&mut BufReader::new(File::open(path)?))?.map(|(_, record)| record))
+    }
+    /// Opens the archive at `path`, authenticating it with `password`.
+    pub fn open(path: impl AsRef<Path>, password: &str) -> Result<ArchiveReader<BufReader<File>>> {
+        ArchiveReader::new(BufReader::new(File::open(path)?), password)
+    }
+    /// Opens the archive at `path` with a password, keyfile, identities
+    /// or any of them.
+    pub fn open_with(path: impl AsRef<Path>, credentials: &Credentials) -> Result<ArchiveReader<BufReader<File>>> {
+        ArchiveReader::with_credentials(BufReader::new(File::open(path)?), credentials)
+    }
+}
+
+/// Compacts the archive at `path` into a new file at `temporary` with a
+/// recovery record of the same size, synced to disk.
+fn compact_into(path: &Path, temporary: &Path, credentials: &Credentials) -> Result<Compaction> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let recovery = RecoveryRecord::read(&mut reader)?.map(|(_, record)| record.percent);
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(temporary)?;
+    let mut writer = BufWriter::new(file);
+    let compaction = compact::compact(&mut reader, &mut writer, credentials)?;
+
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    if let Some(percent) = recovery {
+        recovery::protect(&mut file, percent)?;
+    }
+    file.set_permissions(reader.get_ref().metadata()?.permissions())?;
+    file.sync_all()?;
+    Ok(compaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::read, io::Cursor, path::Path};
+
+    use anyhow::Result;
+
+    use crate::testing::{test_options, test_tree, TEST_PASSWORD};
+
+    use super::Archive;
+
+    #[test]
+    pub fn test_create_list_extract() -> Result<()> {
+        let source = test_tree(&[("tree/README.md", b"hello how art thow"), ("tree/sub/text.txt", b"")])?;
+        let output = tempfile::tempdir()?;
+
+        let archive_path = output.path().join("archive.srs");
+        let mut writer = Archive::create(&archive_path, TEST_PASSWORD, test_options())?;
+        writer.add_path(source.path().join("tree"))?;
+        writer.finish()?;
+
+        let mut reader = Archive::open(&archive_path, TEST_PASSWORD)?;
+        let readme = Path::new("tree/README.md");
+        assert!(reader.files().contains(&&readme.to_path_buf()));
+        assert_eq!(reader.entries().len(), 4);
+
+        let mut extracted = Cursor::new(Vec::new());
+        reader.extract_file(readme, &mut extracted)?;
+        assert_eq!(extracted.into_inner(), b"hello how art thow");
+        let (_, _, node) = reader.entries().iter().find(|(_, _, node)| node.path == readme).unwrap();
+        assert_eq!(node.hash, Some(*blake3::hash(b"hello how art thow").as_bytes()));
+
+        let destination = output.path().join("extracted");
+        reader.extract_all(&destination)?;
+        assert_eq!(read(destination.join(readme))?, b"hello how art thow");
+
+        assert!(Archive::open(&archive_path, "wrong").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_read_legacy_archive() -> Result<()> {
+        let mut reader = Archive::open(Path::new(env!("CARGO_MANIFEST_DIR")).join("archive.srs"), "hello")?;
+
+        let mut extracted = Cursor::new(Vec::new());
+        reader.extract_file("test/README.md", &mut extracted)?;
+        assert_eq!(extracted.into_inner(), b"hello how art thow\n");
+        Ok(())
+    }
+}
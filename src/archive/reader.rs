@@ -0,0 +1,274 @@
+use std::{fs::{hard_link, remove_file, File}, io::{BufWriter, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, Result};
+
+use crate::{error::ArchiveError, ioutils::{create_directory_tree, resolve_extraction_path, transfer_archival_node}, security::credentials::Credentials, structure::{header::ArchiveHeader, node::{ArchivalNode, NodeKind}, table::FileTable}};
+
+use super::{select::link_target, verify::{verify_node, EntryReport, VerifyReport}, EntryReader, ExtractOptions};
+
+/// Reads an existing archive.
+///
+/// The header, trailer and table are authenticated when the reader is
+/// created, entries are decrypted on demand.
+pub struct ArchiveReader<R: Read + Seek> {
+    reader: R,
+    table: FileTable
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    /// Opens an archive, decrypting its file table with `password`.
+    pub fn new(reader: R, password: &str) -> Result<Self> {
+        Self::with_credentials(reader, &Credentials::password(password))
+    }
+    /// Opens an archive with a password, identities or both.
+    pub fn with_credentials(mut reader: R, credentials: &Credentials) -> Result<Self> {
+        let table = FileTable::from_reader(&mut reader, credentials)?;
+        Ok(Self {
+            reader,
+            table
+        })
+    }
+    /// The header of the archive.
+    pub fn header(&self) -> &ArchiveHeader {
+        self.table.header()
+    }
+    /// The file table of the archive.
+    pub fn table(&self) -> &FileTable {
+        &self.table
+    }
+    /// Every entry as (Index, File Position, ArchivalNode).
+    pub fn entries(&self) -> &[(u32, u64, ArchivalNode)] {
+        &self.table.map
+    }
+    /// The paths of every entry.
+    pub fn files(&self) -> Vec<&PathBuf> {
+        self.table.map.iter().map(|(_, _, node)| &node.path).collect()
+    }
+    /// Decrypts the contents of the file stored at `path` into `writer`.
+    pub fn extract_file<W: Write>(&mut self, path: impl AsRef<Path>, writer: &mut W) -> Result<()> {
+        let path = path.as_ref();
+        let entry = self.table.map.iter()
+            .position(|(_, _, node)| node.is_file() && node.path == path)
+            .ok_or_else(|| anyhow!("The archive has no file at {path:?}."))?;
+        self.extract_entry(entry, writer)
+    }
+    /// Opens the file stored at `path` for reading and seeking within it,
+    /// decrypting only the chunks that are read.
+    pub fn open_file(&mut self, path: impl AsRef<Path>) -> Result<EntryReader<'_, R>> {
+        let path = path.as_ref();
+        let entry = self.table.map.iter()
+            .position(|(_, _, node)| node.is_file() && node.path == path)
+            .ok_or_else(|| anyhow!("The archive has no file at {path:?}."))?;
+        self.open_entry(entry)
+    }
+    /// Like [ArchiveReader::open_file] for the `entry`-th entry of the
+    /// table, which must be a regular file.
+    pub fn open_entry(&mut self, entry: usize) -> Result<EntryReader<'_, R>> {
+        let (index, _, node) = self.table.map.get(entry)
+            .ok_or_else(|| anyhow!("The archive has no entry {entry}."))?;
+        if !node.is_file() {
+            return Err(anyhow!("Entry {:?} is not a regular file.", node.path));
+        }
+        let context = self.table.header().chunk_context(*index);
+        EntryReader::new(&mut self.reader, self.table.key(), context, node)
+    }
+    /// Decrypts and authenticates every chunk of every entry without
+    /// keeping the contents, reporting the state of each entry.
+    ///
+    /// The header, trailer and table were authenticated when the reader
+    /// was created. A damaged entry does not stop the walk, it is recorded
+    /// in the report.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for (index, position, node) in &self.table.map {
+            let context = self.table.header().chunk_context(*index);
+            report.entries.push(EntryReport {
+                index: *index,
+                path: node.path.clone(),
+                status: verify_node(&mut self.reader, self.table.key(), &context, *position, node)?
+            });
+        }
+        Ok(report)
+    }
+    /// Decrypts the contents of the `entry`-th entry of the table into
+    /// `writer`, doing nothing for entries that are not regular files.
+    ///
+    /// Fails with [ArchiveError::HashMismatch] once everything is written
+    /// if the contents do not match the hash recorded for them.
+    pub fn extract_entry<W: Write>(&mut self, entry: usize, writer: &mut W) -> Result<()> {
+        let (index, position, node) = self.table.map.get(entry)
+            .ok_or_else(|| anyhow!("The archive has no entry {entry}."))?;
+        if !node.is_file() {
+            return Ok(());
+        }
+        let context = self.table.header().chunk_context(*index);
+
+        self.reader.seek(SeekFrom::Start(*position))?;
+        let hash = transfer_archival_node(&mut self.reader, writer, self.table.key(), &context, &node.compression)?;
+        if node.hash.is_some_and(|expected| hash != expected) {
+            Err(ArchiveError::HashMismatch { path: node.path.clone() })?
+        }
+        Ok(())
+    }
+    /// Extracts every entry beneath `dest`, restoring the recorded
+    /// metadata with the default [ExtractOptions].
+    ///
+    /// Fails with [ArchiveError::UnsafePath](crate::error::ArchiveError::UnsafePath)
+    /// before writing an entry that would land outside of `dest`.
+    pub fn extract_all(&mut self, dest: impl AsRef<Path>) -> Result<()> {
+        self.extract_all_with(dest, &ExtractOptions::default())
+    }
+    /// Extracts the entries `options` select beneath `dest`.
+    ///
+    /// Nothing is written if an include pattern of the selection matches
+    /// no entry.
+    pub fn extract_all_with(&mut self, dest: impl AsRef<Path>, options: &ExtractOptions) -> Result<()> {
+        let dest = dest.as_ref();
+        let mut directories = Vec::new();
+        let mut links = Vec::new();
+        for entry in options.selection.select(&self.table.map)? {
+            let node = &self.table.map[entry].2;
+            let is_directory = node.kind == NodeKind::Directory;
+
+            // Create the directory tree if it does not exist.
+            let path = resolve_extraction_path(dest, &node.path, !is_directory)?;
+            create_directory_tree(&path, !is_directory)?;
+
+            match node.kind.clone() {
+                NodeKind::Directory => {
+                    directories.push((path, entry));
+                    continue;
+                },
+                NodeKind::File => {
+                    let writer = &mut BufWriter::new(File::create(&path)?);
+                    self.extract_entry(entry, writer)?;
+                    writer.flush()?;
+                },
+                NodeKind::Symlink { target } => {
+                    remove_existing(&path)?;
+                    create_symlink(&target, &path)?;
+                },
+                NodeKind::Hardlink { target } => {
+                    links.push((path, entry, target));
+                    continue;
+                }
+            }
+
+            if let Some(metadata) = &self.table.map[entry].2.metadata {
+                metadata.restore(&path, options.ownership)?;
+            }
+        }
+
+        // Links are created once every file is, as the file a link refers
+        // to may be stored after it.
+        for (path, entry, target) in links {
+            let original = link_target(&self.table.map, entry)
+                .map(|original| resolve_extraction_path(dest, &self.table.map[original].2.path, false))
+                .transpose()?
+                .filter(|original| original.symlink_metadata().is_ok());
+            let Some(original) = original else {
+                return Err(anyhow!("Hard link {:?} refers to {target:?}, which has not been extracted.", self.table.map[entry].2.path));
+            };
+            remove_existing(&path)?;
+            // The metadata is shared with, and was restored by, the original.
+            hard_link(original, &path)?;
+        }
+
+        // Directories are finished last so that extracting their contents
+        // neither updates their times nor is refused by their permissions.
+        for (path, entry) in directories.into_iter().rev() {
+            if let Some(metadata) = &self.table.map[entry].2.metadata {
+                metadata.restore(&path, options.ownership)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes whatever is at `path` so a link can be created in its place,
+/// leaving directories alone so that creating the link fails instead.
+fn remove_existing(path: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if !metadata.is_dir() => remove_file(path)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    Err(anyhow!("Cannot create the symbolic link {path:?} to {target:?} on this platform."))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::read, io::Cursor, path::Path};
+
+    use anyhow::Result;
+
+    use crate::{archive::{AddOptions, ArchiveWriter}, structure::compression::{Compression, CompressionAlgorithm}, testing::{test_options, test_tree, TEST_PASSWORD}};
+
+    use super::ArchiveReader;
+
+    #[test]
+    pub fn test_compressed_entries() -> Result<()> {
+        let contents = b"a line of a rather repetitive log\n".repeat(10_000);
+        let source = test_tree(&[("log", &contents)])?;
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), TEST_PASSWORD, test_options())?;
+        writer.add_path_with(source.path().join("log"), &AddOptions {
+            compression: Compression::with_default_level(CompressionAlgorithm::Zstd),
+            ..Default::default()
+        })?;
+        let archive = writer.finish()?.into_inner();
+        assert!(archive.len() < contents.len() / 10);
+
+        let mut reader = ArchiveReader::new(Cursor::new(archive), TEST_PASSWORD)?;
+        assert_eq!(reader.entries()[0].2.compression.algorithm, CompressionAlgorithm::Zstd);
+
+        let mut extracted = Cursor::new(Vec::new());
+        reader.extract_file("log", &mut extracted)?;
+        assert_eq!(extracted.into_inner(), contents);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_links() -> Result<()> {
+        use std::{fs::{hard_link, read_link}, os::unix::fs::{symlink, MetadataExt}};
+
+        use crate::structure::node::NodeKind;
+
+        let source = test_tree(&[("tree/original", b"shared contents")])?;
+        let output = tempfile::tempdir()?;
+        hard_link(source.path().join("tree/original"), source.path().join("tree/copy"))?;
+        symlink("original", source.path().join("tree/relative"))?;
+        symlink("/nonexistent/target", source.path().join("tree/dangling"))?;
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), TEST_PASSWORD, test_options())?;
+        writer.add_path(source.path().join("tree"))?;
+        let archive = writer.finish()?.into_inner();
+
+        let mut reader = ArchiveReader::new(Cursor::new(archive), TEST_PASSWORD)?;
+        let hardlinks = reader.entries().iter()
+            .filter(|(_, _, node)| matches!(node.kind, NodeKind::Hardlink { .. }))
+            .count();
+        assert_eq!(hardlinks, 1);
+
+        let destination = output.path().join("extracted");
+        reader.extract_all(&destination)?;
+        let tree = destination.join("tree");
+        assert_eq!(read_link(tree.join("relative"))?, Path::new("original"));
+        assert_eq!(read_link(tree.join("dangling"))?, Path::new("/nonexistent/target"));
+        assert_eq!(tree.join("original").metadata()?.ino(), tree.join("copy").metadata()?.ino());
+        assert_eq!(read(tree.join("copy"))?, b"shared contents");
+        Ok(())
+    }
+}
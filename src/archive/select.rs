@@ -0,0 +1,158 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+
+use crate::structure::node::{ArchivalNode, NodeKind};
+
+/// Chooses entries of an archive by their stored paths.
+///
+/// A pattern is a path or a glob such as `etc/*.conf` or `**/*.log`. It
+/// selects the entries it matches and, when it matches a directory,
+/// everything beneath it. A pattern without a `/` matches a name at any
+/// depth rather than a path from the root, as in a `.gitignore`.
+///
+/// The default selection holds every entry.
+#[derive(Clone, Debug, Default)]
+pub struct Selection {
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>
+}
+
+#[derive(Clone, Debug)]
+struct Pattern {
+    text: String,
+    matcher: GlobMatcher,
+    /// Whether the pattern matches names rather than paths.
+    anywhere: bool
+}
+
+impl Pattern {
+    fn new(text: &str) -> Result<Self> {
+        let trimmed = text.trim_start_matches("./").trim_start_matches('/').trim_end_matches('/');
+        let matcher = GlobBuilder::new(trimmed)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid pattern {text:?}."))?
+            .compile_matcher();
+        Ok(Self {
+            text: text.to_string(),
+            matcher,
+            anywhere: !trimmed.contains('/')
+        })
+    }
+    /// Whether the pattern matches `path` or one of the directories it
+    /// lies in.
+    fn matches(&self, path: &Path) -> bool {
+        path.ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| match self.anywhere {
+                true => ancestor.file_name().is_some_and(|name| self.matcher.is_match(name)),
+                false => self.matcher.is_match(ancestor)
+            })
+    }
+}
+
+impl Selection {
+    /// Selects the entries matching any of `includes`, or every entry if
+    /// there are none, apart from those matching any of `excludes`.
+    pub fn new<S: AsRef<str>>(includes: &[S], excludes: &[S]) -> Result<Self> {
+        Ok(Self {
+            includes: includes.iter().map(|text| Pattern::new(text.as_ref())).collect::<Result<_>>()?,
+            excludes: excludes.iter().map(|text| Pattern::new(text.as_ref())).collect::<Result<_>>()?
+        })
+    }
+    /// Whether the entry stored under `path` is selected.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.is_included(path) && !self.is_excluded(path)
+    }
+    /// Whether `path` matches an include pattern, or there are none.
+    pub fn is_included(&self, path: &Path) -> bool {
+        self.includes.is_empty() || self.includes.iter().any(|pattern| pattern.matches(path))
+    }
+    /// Whether `path` matches an exclude pattern.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.excludes.iter().any(|pattern| pattern.matches(path))
+    }
+    /// The positions of the selected entries of a file table, in order.
+    ///
+    /// The file a selected hard link refers to is selected as well, since
+    /// the link cannot be extracted without it. Fails if an include
+    /// pattern matches no entry at all, which is usually a typo.
+    pub fn select(&self, map: &[(u32, u64, ArchivalNode)]) -> Result<Vec<usize>> {
+        let mut selected = map.iter().map(|(_, _, node)| self.matches(&node.path)).collect::<Vec<_>>();
+
+        if let Some(pattern) = self.includes.iter().find(|pattern| !map.iter().any(|(_, _, node)| pattern.matches(&node.path))) {
+            return Err(anyhow!("No entry of the archive matches {:?}.", pattern.text));
+        }
+
+        for entry in 0..map.len() {
+            if selected[entry] {
+                if let Some(original) = link_target(map, entry) {
+                    selected[original] = true;
+                }
+            }
+        }
+        Ok((0..map.len()).filter(|entry| selected[*entry]).collect())
+    }
+}
+
+/// The position of the entry the hard link at `entry` ends up referring
+/// to, `None` if it is not a hard link or the file is missing.
+///
+/// Targets are found by path anywhere in the table, since replacing the
+/// target of a link stores its new entry after the link, and links to a
+/// target that was replaced by a link are followed.
+pub(crate) fn link_target(map: &[(u32, u64, ArchivalNode)], entry: usize) -> Option<usize> {
+    let mut current = entry;
+    // Following more links than there are entries means they form a loop.
+    for _ in 0..map.len() {
+        let NodeKind::Hardlink { target } = &map[current].2.kind else {
+            return (current != entry).then_some(current);
+        };
+        current = map.iter().position(|(_, _, node)| node.path == *target)?;
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    use crate::structure::node::{ArchivalNode, NodeKind};
+
+    use super::Selection;
+
+    fn entry(path: &str, kind: NodeKind) -> (u32, u64, ArchivalNode) {
+        (0, 0, ArchivalNode { path: path.into(), kind, ..Default::default() })
+    }
+
+    #[test]
+    pub fn test_selection() -> Result<()> {
+        let map = vec![
+            entry("etc", NodeKind::Directory),
+            entry("etc/nginx", NodeKind::Directory),
+            entry("etc/nginx/nginx.conf", NodeKind::File),
+            entry("etc/hosts", NodeKind::File),
+            entry("var/log/app.log", NodeKind::File),
+            entry("var/hosts", NodeKind::Hardlink { target: "etc/hosts".into() })
+        ];
+
+        assert_eq!(Selection::default().select(&map)?, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(Selection::new(&["etc/nginx/nginx.conf"], &[])?.select(&map)?, vec![2]);
+        // Directories bring their contents along.
+        assert_eq!(Selection::new(&["/etc/nginx/"], &[])?.select(&map)?, vec![1, 2]);
+        assert_eq!(Selection::new(&["etc/*"], &["*.conf"])?.select(&map)?, vec![1, 3]);
+        assert_eq!(Selection::new(&["**/*.log"], &[])?.select(&map)?, vec![4]);
+        // A link brings its target along.
+        assert_eq!(Selection::new(&["var"], &["*.log"])?.select(&map)?, vec![3, 5]);
+
+        assert!(Selection::new(&["etc/missing"], &[])?.select(&map).is_err());
+        assert!(Selection::new(&["etc/[oops"], &[]).is_err());
+        assert!(!Selection::new(&["hosts"], &[])?.matches(Path::new("etc/hostsfile")));
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use std::{fmt, io::{ErrorKind, Read, Seek, SeekFrom}, path::PathBuf};
+
+use anyhow::Result;
+use zeroize::Zeroizing;
+
+use crate::{ioutils::read_byte, security::secure::{read_sealed, ChunkContext, Sealed}, structure::node::ArchivalNode};
+
+/// What verifying an entry found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryStatus {
+    /// Every chunk authenticated and holds what the chunk index records.
+    Ok,
+    /// The chunk starting at `offset` is damaged, moved or not where the
+    /// chunk index places it.
+    CorruptChunk { chunk_index: u64, offset: u64 },
+    /// The data of the entry ends before its final chunk.
+    Truncated { chunk_index: u64 },
+    /// The final chunk is not followed by the end of the entry.
+    MissingTerminator,
+    /// Every chunk is intact but together they do not match the hash
+    /// recorded for the file.
+    HashMismatch
+}
+
+impl fmt::Display for EntryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::CorruptChunk { chunk_index, offset } => write!(f, "corrupt chunk {chunk_index} at offset {offset}"),
+            Self::Truncated { chunk_index } => write!(f, "truncated at chunk {chunk_index}"),
+            Self::MissingTerminator => write!(f, "missing terminator"),
+            Self::HashMismatch => write!(f, "hash mismatch")
+        }
+    }
+}
+
+/// The status of a single entry of a [VerifyReport].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryReport {
+    pub index: u32,
+    pub path: PathBuf,
+    pub status: EntryStatus
+}
+
+/// The outcome of [ArchiveReader::verify](super::ArchiveReader::verify),
+/// one report per entry in the order of the table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub entries: Vec<EntryReport>
+}
+
+impl VerifyReport {
+    /// Whether every entry is intact.
+    pub fn is_ok(&self) -> bool {
+        self.entries.iter().all(|entry| entry.status == EntryStatus::Ok)
+    }
+    /// The entries that are not intact.
+    pub fn damaged(&self) -> impl Iterator<Item = &EntryReport> {
+        self.entries.iter().filter(|entry| entry.status != EntryStatus::Ok)
+    }
+}
+
+/// Walks the chunks of the node written at `position`, decrypting and
+/// decompressing each without keeping the contents.
+///
+/// Damage is reported as the status of the entry, only failures to read
+/// the archive at all are returned as errors.
+pub(crate) fn verify_node<R: Read + Seek>(reader: &mut R, key: &[u8], context: &ChunkContext, position: u64, node: &ArchivalNode) -> Result<EntryStatus> {
+    if !node.is_file() {
+        return Ok(EntryStatus::Ok);
+    }
+    let legacy = *context == ChunkContext::Legacy;
+    reader.seek(SeekFrom::Start(position))?;
+
+    let mut chunk_index = 0;
+    let mut hasher = blake3::Hasher::new();
+    // The position of the next status byte.
+    let mut offset = position;
+    loop {
+        let chunk_offset = offset + 1;
+        let corrupt = EntryStatus::CorruptChunk { chunk_index, offset: chunk_offset };
+        match or_eof(read_byte(reader))? {
+            None => return Ok(EntryStatus::Truncated { chunk_index }),
+            Some(0x00) => {},
+            // Only legacy entries end without a chunk marked as final.
+            Some(0x01) if legacy => return Ok(EntryStatus::Ok),
+            Some(0x01) => return Ok(EntryStatus::Truncated { chunk_index }),
+            Some(_) => return Ok(corrupt)
+        }
+        let location = node.chunks.get(chunk_index as usize);
+        if !legacy && location.map(|location| location.offset) != Some(chunk_offset) {
+            return Ok(corrupt);
+        }
+
+        let sealed = match or_eof(read_chunk(reader))? {
+            None => return Ok(EntryStatus::Truncated { chunk_index }),
+            Some(None) => return Ok(corrupt),
+            Some(Some(sealed)) => sealed
+        };
+        let last = !legacy && chunk_index as usize + 1 == node.chunks.len();
+        let Ok(opened) = context.open_chunk(key, &sealed, chunk_index, last) else {
+            return Ok(corrupt);
+        };
+        match node.compression.decompress_chunk(Zeroizing::new(opened)) {
+            Ok(chunk) if legacy || location.is_some_and(|location| chunk.len() == location.length as usize) => {
+                hasher.update(&chunk);
+            },
+            _ => return Ok(corrupt)
+        }
+
+        offset = chunk_offset + sealed.encoded_len();
+        if last {
+            return Ok(match or_eof(read_byte(reader))? {
+                Some(0x01) if node.hash.is_some_and(|expected| hasher.finalize() != expected) => EntryStatus::HashMismatch,
+                Some(0x01) => EntryStatus::Ok,
+                _ => EntryStatus::MissingTerminator
+            });
+        }
+        chunk_index += 1;
+    }
+}
+
+/// Reads a sealed chunk, or `None` if its length is beyond any chunk.
+fn read_chunk<R: Read>(reader: &mut R) -> Result<Option<Sealed>> {
+    match read_sealed(reader) {
+        Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|io| io.kind() == ErrorKind::InvalidData) => Ok(None),
+        result => result.map(Some)
+    }
+}
+
+/// Turns running out of data into `None`.
+fn or_eof<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|io| io.kind() == ErrorKind::UnexpectedEof) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{sink, Cursor}, path::Path};
+
+    use anyhow::Result;
+
+    use crate::{archive::ArchiveReader, constants::CHUNK_SIZE, error::ArchiveError, structure::{node::ArchivalNode, table::FileTable}, testing::{test_archive, test_header, test_tree, TEST_PASSWORD}};
+
+    use super::EntryStatus;
+
+    #[test]
+    pub fn test_verify() -> Result<()> {
+        let mut archive = test_archive(&[("large", &[7u8; CHUNK_SIZE * 2]), ("small", b"hello"), ("intact", b"world")])?;
+
+        let mut reader = ArchiveReader::new(Cursor::new(archive.clone()), TEST_PASSWORD)?;
+        assert!(reader.verify()?.is_ok());
+        let second_chunk = reader.entries()[0].2.chunks[1];
+        let terminator = reader.entries()[2].1 - 1;
+
+        // Flip a byte of the ciphertext and drop the end of the small file.
+        archive[second_chunk.offset as usize + 20] ^= 0x01;
+        archive[terminator as usize] = 0x00;
+
+        let report = ArchiveReader::new(Cursor::new(archive), TEST_PASSWORD)?.verify()?;
+        let statuses = report.entries.iter().map(|entry| (entry.path.as_path(), entry.status.clone())).collect::<Vec<_>>();
+        assert_eq!(statuses, vec![
+            (Path::new("large"), EntryStatus::CorruptChunk { chunk_index: 1, offset: second_chunk.offset }),
+            (Path::new("small"), EntryStatus::MissingTerminator),
+            (Path::new("intact"), EntryStatus::Ok)
+        ]);
+        assert_eq!(report.damaged().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_hash_mismatch() -> Result<()> {
+        let source = test_tree(&[("file", b"contents")])?;
+
+        let (key, header) = test_header()?;
+        let mut archive = Cursor::new(Vec::new());
+        header.write(&mut archive)?;
+
+        // Intact chunks under a hash recorded for other contents.
+        let mut node = ArchivalNode { path: "file".into(), source: Some(source.path().join("file")), ..Default::default() };
+        let position = node.write(&mut archive, &key, &header.chunk_context(0))?;
+        node.hash = Some(*blake3::hash(b"something else").as_bytes());
+        let mut table = FileTable::new(key, header);
+        table.add(0, position, node);
+        table.write(&mut archive)?;
+
+        let mut reader = ArchiveReader::new(Cursor::new(archive.into_inner()), TEST_PASSWORD)?;
+        assert_eq!(reader.verify()?.entries[0].status, EntryStatus::HashMismatch);
+        let error = reader.extract_entry(0, &mut sink()).unwrap_err();
+        assert_eq!(error.downcast_ref::<ArchiveError>(), Some(&ArchiveError::HashMismatch { path: "file".into() }));
+        Ok(())
+    }
+}
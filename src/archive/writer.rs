@@ -0,0 +1,677 @@
+use std::{collections::{hash_map::Entry, HashMap, HashSet}, fs::{read_link, Metadata}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use walkdir::WalkDir;
+
+use crate::{ioutils::{normalize_entry_path, Truncate}, security::{credentials::{Credentials, Factors}, secure::generate_key, slots::KeySlot}, structure::{compression::Compression, header::ArchiveHeader, metadata::NodeMetadata, node::{ArchivalNode, NodeKind}, recovery::{self, RecoveryRecord}, table::FileTable}};
+
+use super::{AddOptions, CreateOptions, Selection};
+
+/// The files listing entries to skip when [AddOptions::ignore_files] is
+/// set, in the format of `.gitignore`.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// Writes a new archive or adds to a finished one.
+///
+/// The header is written on creation, every added node is encrypted
+/// straight into the writer and the table and trailer are written by
+/// [ArchiveWriter::finish].
+///
+/// Adding an entry under a path already in the archive replaces it. The
+/// chunks of replaced and removed entries stay in the archive until it is
+/// compacted with [Archive::compact](super::Archive::compact).
+pub struct ArchiveWriter<W: Write + Seek> {
+    /// Only taken by [ArchiveWriter::finish].
+    writer: Option<W>,
+    table: FileTable,
+    /// The stored path of every multiply linked file added so far, by
+    /// device and inode.
+    links: HashMap<(u64, u64), PathBuf>,
+    /// The index of the live entry stored under each path.
+    paths: HashMap<PathBuf, u32>,
+    /// The indexes of the entries replaced or removed, dropped from the
+    /// table when it is written.
+    removed: HashSet<u32>,
+    /// How to undo an unfinished append, cleared once it is finished.
+    original: Option<Original<W>>,
+    /// The device and inode of the file the archive is written to, left
+    /// out when walking.
+    output: Option<(u64, u64)>
+}
+
+/// The archive an [ArchiveWriter::append] started from, restored if the
+/// writer is dropped before it is finished.
+struct Original<W> {
+    /// Where the archive ended, its recovery record included.
+    end: u64,
+    /// The size of the recovery record the archive carried, rebuilt once
+    /// the archive is finished.
+    recovery: Option<u8>,
+    truncate: fn(&mut W, u64) -> Result<()>,
+    protect: fn(&mut W, u8) -> Result<RecoveryRecord>
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    /// Starts a new archive at the start of the writer under a random
+    /// key, stored in the first key slot wrapped with `password` and in
+    /// the following ones wrapped to each of `options.recipients`.
+    ///
+    /// More passwords can be added once the archive is finished with
+    /// [ArchiveKeys](super::ArchiveKeys).
+    pub fn new(writer: W, password: &str, options: CreateOptions) -> Result<Self> {
+        Self::with_credentials(writer, &Credentials::password(password), options)
+    }
+    /// Like [ArchiveWriter::new] but without a password, so only the
+    /// identities of `options.recipients` can open the archive.
+    pub fn for_recipients(writer: W, options: CreateOptions) -> Result<Self> {
+        Self::with_credentials(writer, &Credentials::default(), options)
+    }
+    /// Like [ArchiveWriter::new] but the first key slot is wrapped with
+    /// the password and keyfile of `credentials`, whichever are present,
+    /// and is left out if neither is.
+    pub fn with_credentials(mut writer: W, credentials: &Credentials, options: CreateOptions) -> Result<Self> {
+        let key = generate_key();
+        let mut header = ArchiveHeader::new();
+
+        let mut slots = Vec::new();
+        if Factors::of(credentials).is_some() {
+            slots.push(KeySlot::wrap_password(&key, credentials, &options.kdf_params, &header.archive_id)?);
+        }
+        for recipient in &options.recipients {
+            slots.push(KeySlot::wrap_recipient(&key, recipient, &header.archive_id)?);
+        }
+        if slots.is_empty() {
+            return Err(anyhow!("An archive needs a password, a keyfile or a recipient to be opened with."));
+        }
+        if slots.len() > header.key_slots.len() {
+            return Err(anyhow!("An archive has room for {} passwords and recipients, not {}.", header.key_slots.len(), slots.len()));
+        }
+        for (index, slot) in slots.into_iter().enumerate() {
+            header.key_slots[index] = slot;
+        }
+        header.write(&mut writer)?;
+
+        Ok(Self {
+            writer: Some(writer),
+            table: FileTable::new(key, header),
+            links: HashMap::new(),
+            paths: HashMap::new(),
+            removed: HashSet::new(),
+            original: None,
+            output: None
+        })
+    }
+    /// Adds `path` and, if it is a directory, everything beneath it,
+    /// stored under the name of `path`.
+    pub fn add_path(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.add_path_with(path, &AddOptions::default())
+    }
+    /// Adds `path` and everything beneath it, storing the entries under
+    /// the paths described by `options`.
+    ///
+    /// Symbolic links are stored as links unless they are followed, and
+    /// files that are hard linked to one already added are stored as hard
+    /// links to it. Devices, FIFOs and sockets are skipped, as is anything
+    /// the filters of `options` leave out.
+    pub fn add_path_with(&mut self, path: impl AsRef<Path>, options: &AddOptions) -> Result<()> {
+        let path = path.as_ref();
+        options.compression.validate()?;
+        let source = match &options.base {
+            Some(base) => base.join(path),
+            None => path.to_path_buf()
+        };
+        let relative = options.base.as_ref()
+            .and_then(|base| source.strip_prefix(base).ok())
+            .and_then(|relative| normalize_entry_path(relative).ok());
+        let root = match relative {
+            Some(relative) => relative,
+            None => match source.file_name() {
+                Some(name) => PathBuf::from(name),
+                // Paths such as `.` are stored under the name of the directory they refer to.
+                None => source.canonicalize()?.file_name().map(PathBuf::from).unwrap_or_default()
+            }
+        };
+
+        let mut ignored = IgnoreFiles::default();
+        let mut walker = WalkDir::new(&source)
+            .follow_links(options.follow_symlinks)
+            .same_file_system(options.one_file_system)
+            .into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = entry?;
+            let is_dir = entry.file_type().is_dir();
+            if options.ignore_files && entry.depth() > 0 && ignored.matches(entry.path(), is_dir) {
+                if is_dir {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            if is_dir && options.ignore_files {
+                ignored.load(entry.path())?;
+            }
+            let relative = root.join(entry.path().strip_prefix(&source)?);
+
+            let stored = normalize_entry_path(&relative)?.components().skip(options.strip_components).collect::<PathBuf>();
+            if stored.as_os_str().is_empty() {
+                continue;
+            }
+            let stored = match &options.prefix {
+                Some(prefix) => normalize_entry_path(prefix)?.join(stored),
+                None => stored
+            };
+
+            if options.selection.is_excluded(&stored) {
+                if is_dir {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            if !options.selection.is_included(&stored) {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            if self.output.is_some() && file_id(&metadata) == self.output {
+                // The archive would hold a partial copy of itself.
+                continue;
+            }
+            if metadata.is_file() && options.max_file_size.is_some_and(|max| metadata.len() > max) {
+                continue;
+            }
+            let file_type = entry.file_type();
+            let kind = if file_type.is_dir() {
+                NodeKind::Directory
+            } else if file_type.is_symlink() {
+                NodeKind::Symlink { target: read_link(entry.path())? }
+            } else if !file_type.is_file() {
+                // Devices, FIFOs and sockets are not supported yet.
+                continue;
+            } else if let Some(target) = self.hardlink_target(&metadata, &stored) {
+                NodeKind::Hardlink { target }
+            } else {
+                NodeKind::File
+            };
+            let compression = match kind {
+                NodeKind::File => options.compression,
+                _ => Compression::NONE
+            };
+
+            self.add_node(ArchivalNode {
+                path: stored,
+                kind,
+                compression,
+                metadata: Some(NodeMetadata::from_metadata(&metadata)),
+                chunks: Vec::new(),
+                hash: None,
+                source: Some(entry.path().to_path_buf())
+            })?;
+        }
+        Ok(())
+    }
+    /// Leaves the file `metadata` describes out of every walk, for the
+    /// file the archive is written to.
+    pub fn skip_file(&mut self, metadata: &Metadata) {
+        self.output = file_id(metadata);
+    }
+    /// Returns the stored path of the file `metadata` describes if another
+    /// link to it was already added, otherwise remembers it as `stored`.
+    #[cfg(unix)]
+    fn hardlink_target(&mut self, metadata: &Metadata, stored: &Path) -> Option<PathBuf> {
+        use std::os::unix::fs::MetadataExt;
+
+        if metadata.nlink() < 2 {
+            return None;
+        }
+        match self.links.entry((metadata.dev(), metadata.ino())) {
+            Entry::Occupied(target) => Some(target.get().clone()),
+            Entry::Vacant(vacant) => {
+                vacant.insert(stored.to_path_buf());
+                None
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    fn hardlink_target(&mut self, _: &Metadata, _: &Path) -> Option<PathBuf> {
+        None
+    }
+    /// Adds a single node, returning its index within the table.
+    ///
+    /// An entry already stored under the path of the node is replaced.
+    pub fn add_node(&mut self, mut node: ArchivalNode) -> Result<u32> {
+        let index = self.table.next_index()?;
+        let context = self.table.header().chunk_context(index);
+
+        let writer = self.writer.as_mut().expect("the writer until finished");
+        let position = node.write(writer, self.table.key(), &context)?;
+        if let Some(replaced) = self.paths.insert(node.path.clone(), index) {
+            self.removed.insert(replaced);
+        }
+        self.table.add(index, position, node);
+        Ok(index)
+    }
+    /// Removes the entries `selection` picks, together with everything
+    /// beneath them, returning their paths.
+    ///
+    /// A selection without include patterns picks every entry. Fails if
+    /// nothing is picked or if a hard link left behind refers to a
+    /// removed file.
+    pub fn remove(&mut self, selection: &Selection) -> Result<Vec<PathBuf>> {
+        let is_live = |index: &u32| !self.removed.contains(index);
+        let picked = self.table.map.iter()
+            .filter(|(index, _, node)| is_live(index) && selection.matches(&node.path))
+            .map(|(index, _, node)| (*index, node.path.clone()))
+            .collect::<HashMap<_, _>>();
+        if picked.is_empty() {
+            return Err(anyhow!("No entry of the archive matches the paths to remove."));
+        }
+
+        let removed_paths = picked.values().collect::<HashSet<_>>();
+        for (index, _, node) in &self.table.map {
+            if let NodeKind::Hardlink { target } = &node.kind {
+                if is_live(index) && !picked.contains_key(index) && removed_paths.contains(target) {
+                    return Err(anyhow!("{:?} is a hard link to {target:?}, remove it as well.", node.path));
+                }
+            }
+        }
+
+        let mut paths = Vec::new();
+        for (_, _, node) in self.table.map.iter().filter(|(index, _, _)| picked.contains_key(index)) {
+            self.paths.remove(&node.path);
+            paths.push(node.path.clone());
+        }
+        self.removed.extend(picked.keys());
+        Ok(paths)
+    }
+    /// Writes the file table and the trailer, returning the writer.
+    ///
+    /// If this fails when appending, the archive is restored as it was.
+    pub fn finish(mut self) -> Result<W> {
+        self.table.remove(&self.removed);
+        let writer = self.writer.as_mut().expect("the writer until finished");
+        self.table.write(writer)?;
+        if let Some(original) = &self.original {
+            // What an interrupted append left may have reached further.
+            let end = writer.stream_position()?;
+            (original.truncate)(writer, end)?;
+            if let Some(percent) = original.recovery {
+                (original.protect)(writer, percent)?;
+            }
+        }
+        writer.flush()?;
+
+        self.original = None;
+        Ok(self.writer.take().expect("the writer until finished"))
+    }
+}
+
+impl<W: Write + Seek> Drop for ArchiveWriter<W> {
+    /// Cuts an unfinished append off where the archive used to end,
+    /// which leaves it as it was.
+    fn drop(&mut self) {
+        if let (Some(original), Some(writer)) = (self.original.take(), self.writer.as_mut()) {
+            let _ = (original.truncate)(writer, original.end);
+            let _ = writer.flush();
+        }
+    }
+}
+
+impl<W: Read + Write + Seek + Truncate> ArchiveWriter<W> {
+    /// Reopens the finished archive in `writer` to add entries to it or
+    /// remove them, unlocking it with `credentials`.
+    ///
+    /// New entries are written after the old trailer and recovery record
+    /// and [ArchiveWriter::finish] writes a table listing both the old and
+    /// the new entries after them, so only the new data is written. The
+    /// old table, trailer and record are left in place until the archive
+    /// is compacted, and a recovery record is rebuilt at the same size by
+    /// `finish`.
+    ///
+    /// Until `finish` succeeds the archive reads as it was: dropping the
+    /// writer cuts off everything written since, and readers skip what an
+    /// append interrupted by a crash left behind, see
+    /// [archive_end](recovery::archive_end).
+    pub fn append(mut writer: W, credentials: &Credentials) -> Result<Self> {
+        let table = FileTable::from_reader(&mut writer, credentials)?;
+        if table.header().is_legacy() {
+            return Err(anyhow!("Legacy archives cannot be appended to, extract and recreate the archive instead."));
+        }
+        let recovery = RecoveryRecord::read(&mut writer)?.map(|(_, record)| record.percent);
+        let end = recovery::archive_end(&mut writer)?;
+        writer.seek(SeekFrom::Start(end))?;
+
+        let paths = table.map.iter().map(|(index, _, node)| (node.path.clone(), *index)).collect();
+        Ok(Self {
+            writer: Some(writer),
+            table,
+            links: HashMap::new(),
+            paths,
+            removed: HashSet::new(),
+            original: Some(Original { end, recovery, truncate: W::truncate, protect: recovery::protect }),
+            output: None
+        })
+    }
+}
+
+/// The device and inode of the file `metadata` describes.
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The ignore files found in the directories being added, by directory.
+#[derive(Default)]
+struct IgnoreFiles {
+    matchers: HashMap<PathBuf, Gitignore>
+}
+
+impl IgnoreFiles {
+    /// Reads the ignore files of `directory`, if it has any.
+    fn load(&mut self, directory: &Path) -> Result<()> {
+        let mut builder = GitignoreBuilder::new(directory);
+        let mut found = false;
+        for name in IGNORE_FILES {
+            let file = directory.join(name);
+            if file.is_file() {
+                if let Some(error) = builder.add(&file) {
+                    return Err(anyhow!("Failed to read the ignore file {file:?}: {error}"));
+                }
+                found = true;
+            }
+        }
+        if found {
+            self.matchers.insert(directory.to_path_buf(), builder.build()?);
+        }
+        Ok(())
+    }
+    /// Whether the ignore files of the directories `path` lies in skip
+    /// it, the closest one deciding.
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        path.ancestors().skip(1)
+            .filter_map(|directory| self.matchers.get(directory))
+            .map(|matcher| matcher.matched(path, is_dir))
+            .find(|matched| !matched.is_none())
+            .is_some_and(|matched| matched.is_ignore())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{create_dir_all, read}, io::Cursor, path::Path};
+
+    use anyhow::Result;
+    use zeroize::Zeroizing;
+
+    use crate::{archive::{AddOptions, Archive, ArchiveReader, CreateOptions, Selection}, security::{credentials::{Credentials, Keyfile}, recipients::Identity}, testing::{test_options, test_tree, TEST_PASSWORD}};
+
+    use super::ArchiveWriter;
+
+    #[test]
+    pub fn test_append() -> Result<()> {
+        let source = test_tree(&[("monday.log", b"started"), ("tuesday.log", b"ok")])?;
+        let output = tempfile::tempdir()?;
+
+        let archive_path = output.path().join("archive.srs");
+        let mut writer = Archive::create(&archive_path, TEST_PASSWORD, test_options())?;
+        writer.add_path(source.path().join("monday.log"))?;
+        writer.finish()?;
+
+        assert!(Archive::append(&archive_path, "wrong").is_err());
+        let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+        appender.add_path(source.path().join("tuesday.log"))?;
+        appender.finish()?;
+
+        let mut reader = Archive::open(&archive_path, TEST_PASSWORD)?;
+        assert_eq!(reader.files(), vec![Path::new("monday.log"), Path::new("tuesday.log")]);
+        for (name, contents) in [("monday.log", &b"started"[..]), ("tuesday.log", b"ok")] {
+            let mut extracted = Cursor::new(Vec::new());
+            reader.extract_file(name, &mut extracted)?;
+            assert_eq!(extracted.into_inner(), contents);
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_failed_append_leaves_archive() -> Result<()> {
+        let source = test_tree(&[("kept", b"contents"), ("added", b"more")])?;
+        let output = tempfile::tempdir()?;
+
+        let archive_path = output.path().join("archive.srs");
+        let mut writer = Archive::create(&archive_path, TEST_PASSWORD, test_options())?;
+        writer.add_path(source.path().join("kept"))?;
+        writer.finish()?;
+        // Restoring an archive rebuilds its recovery record byte for byte.
+        Archive::protect(&archive_path, 50)?;
+        let original = read(&archive_path)?;
+
+        // Dropped before it is finished.
+        let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+        appender.add_path(source.path().join("added"))?;
+        drop(appender);
+        assert_eq!(read(&archive_path)?, original);
+
+        // Failing to finish, the table cannot hold a path that is not UTF-8.
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+            use crate::structure::node::ArchivalNode;
+
+            let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+            appender.add_path(source.path().join("added"))?;
+            appender.add_node(ArchivalNode {
+                path: OsStr::from_bytes(b"bad\xff").into(),
+                source: Some(source.path().join("added")),
+                ..Default::default()
+            })?;
+            assert!(appender.finish().is_err());
+            assert_eq!(read(&archive_path)?, original);
+        }
+
+        // Killed before it is finished, which leaves the new chunks behind
+        // the old recovery record.
+        let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+        appender.add_path(source.path().join("added"))?;
+        std::mem::forget(appender);
+        assert!(read(&archive_path)?.len() > original.len());
+        assert!(Archive::repair(&archive_path)?.is_empty());
+
+        let mut reader = Archive::open(&archive_path, TEST_PASSWORD)?;
+        assert_eq!(reader.files(), vec![Path::new("kept")]);
+        let mut extracted = Vec::new();
+        reader.extract_file("kept", &mut extracted)?;
+        assert_eq!(extracted, b"contents");
+
+        // The next append writes over what was left.
+        let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+        appender.add_path(source.path().join("added"))?;
+        appender.finish()?;
+        assert_eq!(Archive::open(&archive_path, TEST_PASSWORD)?.files(), vec![Path::new("kept"), Path::new("added")]);
+        assert_eq!(Archive::recovery_record(&archive_path)?.map(|record| record.percent), Some(50));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn test_replace_link_target() -> Result<()> {
+        use std::{fs::{hard_link, remove_file, write}, os::unix::fs::MetadataExt};
+
+        use crate::{archive::ExtractOptions, structure::node::NodeKind};
+
+        let source = test_tree(&[("src/a", b"old")])?;
+        hard_link(source.path().join("src/a"), source.path().join("src/b"))?;
+        let output = tempfile::tempdir()?;
+        let stored = AddOptions {
+            base: Some(source.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        let archive_path = output.path().join("archive.srs");
+        let mut writer = Archive::create(&archive_path, TEST_PASSWORD, test_options())?;
+        writer.add_path_with("src", &stored)?;
+        writer.finish()?;
+
+        // Replace whichever of the two was stored as the file.
+        let reader = Archive::open(&archive_path, TEST_PASSWORD)?;
+        let (_, _, file) = reader.entries().iter().find(|(_, _, node)| node.kind == NodeKind::File).expect("a file");
+        let (file, link) = (file.path.clone(), if file.path.ends_with("a") { "src/b" } else { "src/a" });
+        remove_file(source.path().join(&file))?;
+        write(source.path().join(&file), b"new")?;
+        let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+        appender.add_path_with(&file, &stored)?;
+        appender.finish()?;
+
+        let mut reader = Archive::open(&archive_path, TEST_PASSWORD)?;
+        let destination = output.path().join("extracted");
+        reader.extract_all(&destination)?;
+        assert_eq!(read(destination.join(link))?, b"new");
+        assert_eq!(destination.join(link).metadata()?.ino(), destination.join(&file).metadata()?.ino());
+
+        // Selecting the link brings the file it refers to along.
+        let destination = output.path().join("link");
+        reader.extract_all_with(&destination, &ExtractOptions {
+            selection: Selection::new(&[link], &[])?,
+            ..Default::default()
+        })?;
+        assert_eq!(read(destination.join(link))?, b"new");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn test_skips_own_archive() -> Result<()> {
+        let source = test_tree(&[("file", b"contents")])?;
+
+        let archive_path = source.path().join("self.srs");
+        let mut writer = Archive::create(&archive_path, TEST_PASSWORD, test_options())?;
+        writer.add_path(source.path())?;
+        writer.finish()?;
+
+        let mut appender = Archive::append(&archive_path, TEST_PASSWORD)?;
+        appender.add_path(&archive_path)?;
+        appender.finish()?;
+
+        let name = source.path().file_name().map(Path::new).expect("a named directory");
+        assert_eq!(Archive::open(&archive_path, TEST_PASSWORD)?.files(), vec![name, &name.join("file")]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_stored_paths() -> Result<()> {
+        let source = test_tree(&[("project/src/main.rs", b"fn main() {}"), ("logs/today.log", b"")])?;
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), TEST_PASSWORD, test_options())?;
+        writer.add_path_with("project", &AddOptions {
+            base: Some(source.path().to_path_buf()),
+            strip_components: 1,
+            ..Default::default()
+        })?;
+        writer.add_path_with(source.path().join("logs"), &AddOptions {
+            prefix: Some("var".into()),
+            ..Default::default()
+        })?;
+        let archive = writer.finish()?.into_inner();
+
+        let reader = ArchiveReader::new(Cursor::new(archive), TEST_PASSWORD)?;
+        assert_eq!(reader.files(), vec![
+            Path::new("src"),
+            Path::new("src/main.rs"),
+            Path::new("var/logs"),
+            Path::new("var/logs/today.log")
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_add_filters() -> Result<()> {
+        let source = test_tree(&[
+            ("repo/.gitignore", b"target/\n"),
+            ("repo/src/main.rs", b"fn main() {}"),
+            ("repo/src/.ignore", b"*.bak\n"),
+            ("repo/src/main.rs.bak", b""),
+            ("repo/target/debug/repo", b""),
+            ("repo/build.log", b""),
+            ("repo/large.bin", &[0u8; 4096])
+        ])?;
+        let repo = source.path().join("repo");
+        create_dir_all(repo.join("target/debug"))?;
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), TEST_PASSWORD, test_options())?;
+        writer.add_path_with(&repo, &AddOptions {
+            selection: Selection::new(&[] as &[&str], &["*.log"])?,
+            ignore_files: true,
+            max_file_size: Some(1024),
+            ..Default::default()
+        })?;
+        let archive = writer.finish()?.into_inner();
+
+        let reader = ArchiveReader::new(Cursor::new(archive), TEST_PASSWORD)?;
+        let mut files = reader.files();
+        files.sort();
+        assert_eq!(files, vec![
+            Path::new("repo"),
+            Path::new("repo/.gitignore"),
+            Path::new("repo/src"),
+            Path::new("repo/src/.ignore"),
+            Path::new("repo/src/main.rs")
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_recipients() -> Result<()> {
+        let source = test_tree(&[("secret", b"for ops only")])?;
+
+        let ops = [Identity::generate(), Identity::generate()];
+        let options = CreateOptions {
+            recipients: ops.iter().map(Identity::recipient).collect(),
+            ..Default::default()
+        };
+        let mut writer = ArchiveWriter::for_recipients(Cursor::new(Vec::new()), options)?;
+        writer.add_path(source.path().join("secret"))?;
+        let archive = writer.finish()?.into_inner();
+
+        let credentials = Credentials::identities(vec![ops[1].clone()]);
+        let mut reader = ArchiveReader::with_credentials(Cursor::new(archive.clone()), &credentials)?;
+        let mut extracted = Cursor::new(Vec::new());
+        reader.extract_file("secret", &mut extracted)?;
+        assert_eq!(extracted.into_inner(), b"for ops only");
+
+        let outsider = Credentials::identities(vec![Identity::generate()]);
+        assert!(ArchiveReader::with_credentials(Cursor::new(archive.clone()), &outsider).is_err());
+        assert!(ArchiveReader::new(Cursor::new(archive), "").is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_keyfile() -> Result<()> {
+        let keyfile = Keyfile::from_contents(b"random bytes");
+        let credentials = Credentials {
+            password: Some(Zeroizing::new("weak".to_string())),
+            keyfile: Some(keyfile.clone()),
+            ..Default::default()
+        };
+        let archive = ArchiveWriter::with_credentials(Cursor::new(Vec::new()), &credentials, test_options())?.finish()?.into_inner();
+
+        ArchiveReader::with_credentials(Cursor::new(archive.clone()), &credentials)?;
+        // Neither factor opens the archive on its own.
+        assert!(ArchiveReader::new(Cursor::new(archive.clone()), "weak").is_err());
+        let keyfile_only = Credentials {
+            keyfile: Some(keyfile),
+            ..Default::default()
+        };
+        assert!(ArchiveReader::with_credentials(Cursor::new(archive), &keyfile_only).is_err());
+
+        // Nothing to open the archive with.
+        assert!(ArchiveWriter::with_credentials(Cursor::new(Vec::new()), &Credentials::default(), CreateOptions::default()).is_err());
+        Ok(())
+    }
+}
@@ -7,3 +7,25 @@ pub const CHUNK_SIZE: usize = 131_072;
 
 /// The length of a salt in bytes.
 pub const SALT_LENGTH_IN_BYTES: usize = 32;
+
+/// The length of the random id identifying an archive in bytes.
+pub const ARCHIVE_ID_LENGTH_IN_BYTES: usize = 16;
+
+/// The magic bytes every versioned archive starts with.
+pub const MAGIC: [u8; 6] = *b"SONORS";
+
+/// The archive format version written by this build, the only one it
+/// reads apart from legacy archives. Raised whenever a released layout
+/// changes, readers of the new version keep reading the old ones.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// The format version assigned to archives written before the header
+/// existed, which start with a raw salt.
+pub const LEGACY_FORMAT_VERSION: u16 = 0;
+
+/// The number of key slots in the header of every versioned archive.
+pub const KEY_SLOT_COUNT: usize = 8;
+
+/// The size of a key slot in bytes, padding included, so any slot can
+/// be rewritten in place.
+pub const KEY_SLOT_LENGTH: usize = 128;
@@ -0,0 +1,41 @@
+use std::{fmt, path::PathBuf};
+
+/// Failures callers may want to tell apart from one another.
+///
+/// These travel inside an [anyhow::Error] like every other error in the
+/// crate and can be recovered with `downcast_ref::<ArchiveError>()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ArchiveError {
+    /// The archive header, trailer or table failed authentication: the
+    /// password is incorrect or the archive has been tampered with.
+    Authentication,
+    /// A chunk failed authentication: it was corrupted, moved within its
+    /// entry or spliced in from another entry or archive.
+    ChunkAuthentication { file_index: u32, chunk_index: u64 },
+    /// The entry ends before the chunk marked as final.
+    Truncated { file_index: u32, chunk_index: u64 },
+    /// More chunks follow the chunk marked as final.
+    TrailingChunks { file_index: u32, chunk_index: u64 },
+    /// The contents of a file do not match the hash recorded for it.
+    HashMismatch { path: PathBuf },
+    /// Verification found entries that are damaged.
+    DamagedEntries { count: usize },
+    /// An entry would be written outside of the extraction destination.
+    UnsafePath { path: PathBuf, reason: &'static str }
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Authentication => write!(f, "The archive could not be authenticated: the password is incorrect or the archive has been tampered with."),
+            Self::ChunkAuthentication { file_index, chunk_index } => write!(f, "Chunk {chunk_index} of entry {file_index} failed authentication, it is corrupted or has been moved."),
+            Self::Truncated { file_index, chunk_index } => write!(f, "Entry {file_index} is truncated, its data ends at chunk {chunk_index} before the final chunk."),
+            Self::TrailingChunks { file_index, chunk_index } => write!(f, "Entry {file_index} has unexpected data after its final chunk {chunk_index}."),
+            Self::HashMismatch { path } => write!(f, "The contents of {path:?} do not match the hash recorded for them."),
+            Self::DamagedEntries { count } => write!(f, "Verification found damaged entries: {count}."),
+            Self::UnsafePath { path, reason } => write!(f, "Refusing entry {path:?}: {reason}.")
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
@@ -1,22 +1,102 @@
-use std::{fs::create_dir_all, io::{Read, Seek, Write}, path::{Path, PathBuf}};
+use std::{fs::{create_dir_all, remove_file, File}, io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write}, path::{Component, Path, PathBuf}};
 use anyhow::{Result, anyhow};
+use zeroize::Zeroizing;
 
-use crate::security::secure::read_encrypted;
+use crate::{error::ArchiveError, security::secure::{read_sealed, ChunkContext}, structure::compression::Compression};
 
-pub fn transfer_archival_node<R: Read + Seek, W: Write + Seek>(reader: &mut R, writer: &mut W, key: &[u8]) -> Result<()>{
+/// Decrypts the chunks of a node written by
+/// [ArchivalNode::write](crate::structure::node::ArchivalNode::write)
+/// into the writer, decompressing them as described by `compression`,
+/// and returns the BLAKE3 hash of what was written.
+///
+/// Fails with an [ArchiveError] if the chunks were reordered, spliced
+/// from elsewhere or truncated.
+pub fn transfer_archival_node<R: Read + Seek, W: Write>(reader: &mut R, writer: &mut W, key: &[u8], context: &ChunkContext, compression: &Compression) -> Result<blake3::Hash>{
+    let mut hasher = blake3::Hasher::new();
+    if *context == ChunkContext::Legacy {
+        loop {
+            let status = read_byte(reader)?;
+            if status == 0x01 {
+                break
+            }
+            let chunk = Zeroizing::new(read_sealed(reader)?.open(key, &[])?);
+            hasher.update(&chunk);
+            writer.write_all(&chunk)?;
+        }
+        return Ok(hasher.finalize());
+    }
 
+    let file_index = context.file_index();
+    let mut chunk_index = 0;
+    // Running out of data anywhere in the sequence means it was cut short,
+    // a chunk longer than any chunk can be that it was damaged.
+    let truncated = |e: anyhow::Error, chunk_index| match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() == ErrorKind::UnexpectedEof => ArchiveError::Truncated { file_index, chunk_index }.into(),
+        Some(io) if io.kind() == ErrorKind::InvalidData => ArchiveError::ChunkAuthentication { file_index, chunk_index }.into(),
+        _ => e
+    };
 
+    if read_status(reader).map_err(|e| truncated(e, 0))? != 0x00 {
+        Err(ArchiveError::Truncated { file_index, chunk_index })?
+    }
+    let mut sealed = read_sealed(reader).map_err(|e| truncated(e, 0))?;
     loop {
-        let status = read_byte(reader)?;
-        if status == 0x01 {
+        let last = read_status(reader).map_err(|e| truncated(e, chunk_index))? == 0x01;
+        let chunk = compression.decompress_chunk(Zeroizing::new(context.open_chunk(key, &sealed, chunk_index, last)?))?;
+        hasher.update(&chunk);
+        writer.write_all(&chunk)?;
+        if last {
             break
         }
-       
-       // let mut buf = vec![0u8; read_u32(reader)? as usize];
-       // reader.read_exact(&mut buf)?;        
-        writer.write_all(&read_encrypted(reader, key)?)?;
+
+        chunk_index += 1;
+        sealed = read_sealed(reader).map_err(|e| truncated(e, chunk_index))?;
     }   
-    Ok(())
+    Ok(hasher.finalize())
+}
+
+/// Copies the chunks of a node written by
+/// [ArchivalNode::write](crate::structure::node::ArchivalNode::write)
+/// as they are, without decrypting them.
+///
+/// Returns where each chunk starts, past its status byte, counted from
+/// the start of the node.
+pub fn copy_archival_node<R: Read + Seek, W: Write>(reader: &mut R, writer: &mut W) -> Result<Vec<u64>> {
+    let mut offsets = Vec::new();
+    let mut length = 0;
+    while read_status(reader)? == 0x00 {
+        writer.write_all(&[0x00])?;
+        offsets.push(length + 1);
+
+        let sealed = read_sealed(reader)?;
+        sealed.write(writer)?;
+        length += 1 + sealed.encoded_len();
+    }
+    writer.write_all(&[0x01])?;
+    Ok(offsets)
+}
+
+/// Reads a chunk status byte, `0x00` for a chunk and `0x01` for the end.
+fn read_status<R: Read + Seek>(reader: &mut R) -> Result<u8> {
+    match read_byte(reader)? {
+        status @ (0x00 | 0x01) => Ok(status),
+        other => Err(anyhow!("Expected a chunk status byte but found 0x{other:02x}."))
+    }
+}
+
+/// Reads until the buffer is full or the reader is exhausted, returning
+/// the number of bytes read.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => Err(e)?
+        }
+    }
+    Ok(filled)
 }
 
 pub fn create_directory_tree(path: impl AsRef<Path>, is_file: bool) -> Result<()> {
@@ -25,31 +105,174 @@ pub fn create_directory_tree(path: impl AsRef<Path>, is_file: bool) -> Result<()
     if path.exists() {
         return Ok(())
     }
-    println!("Path: {:?}", path);
     if is_file {
         if let Some(parent_directory) = path.parent() {
-            println!("-> {:?}", parent_directory);
-            create_dir_all(&parent_directory)?;
+            create_dir_all(parent_directory)?;
         }
     } else {
-        create_dir_all(&path)?;
+        create_dir_all(path)?;
         
     }
     
     Ok(())
 }
 
+/// Normalises a path read from an archive into a relative path.
+///
+/// Leading root and prefix components are stripped and `.` components
+/// dropped, while `..` components are refused with
+/// [ArchiveError::UnsafePath] as they could climb out of the
+/// extraction destination.
+pub fn normalize_entry_path(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {},
+            Component::ParentDir => Err(ArchiveError::UnsafePath { path: path.to_path_buf(), reason: "the path contains a `..` component" })?,
+            Component::Normal(part) => normalized.push(part)
+        }
+    }
+    Ok(normalized)
+}
+
+/// Resolves where an entry is extracted to beneath `dest`.
+///
+/// Every component that already exists as a symbolic link must resolve
+/// to somewhere within `dest`, except for the final component of a leaf
+/// which is removed so that the file replaces the link instead of being
+/// written through it.
+pub fn resolve_extraction_path(dest: &Path, entry: &Path, is_leaf: bool) -> Result<PathBuf> {
+    let relative = normalize_entry_path(entry)?;
+    let unsafe_path = |reason| ArchiveError::UnsafePath { path: entry.to_path_buf(), reason };
+
+    create_dir_all(dest)?;
+    let root = dest.canonicalize()?;
+
+    let mut path = dest.to_path_buf();
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        path.push(component);
+
+        let is_symlink = path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if !is_symlink {
+            continue;
+        }
+        if is_leaf && components.peek().is_none() {
+            remove_file(&path)?;
+            break;
+        }
+        match path.canonicalize() {
+            Ok(target) if target.starts_with(&root) => {},
+            Ok(_) => Err(unsafe_path("it would be written through a symbolic link leading outside of the destination"))?,
+            Err(_) => Err(unsafe_path("it would be written through a dangling symbolic link"))?
+        }
+    }
+    Ok(path)
+}
+
+/// The position just past the last of `needles` found in the reader
+/// ending at or before `before`, searching backwards a block at a time.
+pub fn rfind<R: Read + Seek>(reader: &mut R, needles: &[&[u8; 8]], before: u64) -> Result<Option<u64>> {
+    const BLOCK_LENGTH: u64 = 65_536;
+
+    let mut end = before;
+    let mut block = Vec::new();
+    while end >= 8 {
+        let start = end.saturating_sub(BLOCK_LENGTH);
+        block.resize((end - start) as usize, 0);
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut block)?;
+        if let Some(at) = block.windows(8).rposition(|window| needles.iter().any(|needle| window == needle.as_slice())) {
+            return Ok(Some(start + at as u64 + 8));
+        }
+        if start == 0 {
+            break;
+        }
+        // Overlap the blocks so a needle across their boundary is found.
+        end = start + 7;
+    }
+    Ok(None)
+}
+
+/// A writer that can be cut short, so a file rewritten in place keeps
+/// nothing of its old contents past its new end.
+pub trait Truncate {
+    fn truncate(&mut self, length: u64) -> Result<()>;
+}
+
+impl Truncate for File {
+    fn truncate(&mut self, length: u64) -> Result<()> {
+        self.set_len(length)?;
+        Ok(())
+    }
+}
+
+impl Truncate for Cursor<Vec<u8>> {
+    fn truncate(&mut self, length: u64) -> Result<()> {
+        self.get_mut().truncate(length.try_into()?);
+        Ok(())
+    }
+}
+
+/// A writer counting its position from where it was started, for
+/// recording where things go without asking a buffered writer, which
+/// would flush it.
+pub struct PositionWriter<W: Write> {
+    inner: W,
+    position: u64
+}
+
+impl<W: Write> PositionWriter<W> {
+    /// Wraps `inner`, which is at `position`.
+    pub fn new(inner: W, position: u64) -> Self {
+        Self { inner, position }
+    }
+    /// Where the next byte written goes.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<W: Write> Write for PositionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub fn write_pathbuf<T: Write + Seek>(writer: &mut T, buf: &PathBuf) -> Result<()> {
     
     let path_bytes = buf.to_str()
         .ok_or_else(|| anyhow!("Failed to represent path {:?} as UTF-8 bytes.", buf))?.as_bytes();
     writer.write_all(&(path_bytes.len() as u32).to_le_bytes())?;
-    writer.write_all(&path_bytes)?;
+    writer.write_all(path_bytes)?;
 
     Ok(())
 }
 
+pub fn write_string<T: Write + Seek>(writer: &mut T, value: &str) -> Result<()> {
+    write_u32(writer, value.len().try_into()?)?;
+    writer.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+pub fn read_string<T: Read + Seek>(reader: &mut T) -> Result<String> {
+    let mut buf = vec![0u8; read_u32(reader)? as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Reads an entry path, normalised by [normalize_entry_path].
 pub fn read_pathbuf<T: Read + Seek>(reader: &mut T) -> Result<PathBuf> {
+    normalize_entry_path(&read_raw_pathbuf(reader)?)
+}
+
+/// Reads a path exactly as it was written, such as a symbolic link target.
+pub fn read_raw_pathbuf<T: Read + Seek>(reader: &mut T) -> Result<PathBuf> {
     let path_length = read_u32(reader)?;
 
     let mut buf = vec![0u8; path_length as usize];
@@ -59,12 +282,50 @@ pub fn read_pathbuf<T: Read + Seek>(reader: &mut T) -> Result<PathBuf> {
 }
 
 
+pub fn write_u64<W: Write>(writer: &mut W, number: u64) -> Result<()> {
+    writer.write_all(&number.to_le_bytes())?;
+    Ok(())
+}
+
 pub fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
     let buf = &mut [0u8; 8];
     reader.read_exact(buf)?;
     Ok(u64::from_le_bytes(*buf))
 }
 
+pub fn write_u16<W: Write>(writer: &mut W, number: u16) -> Result<()> {
+    writer.write_all(&number.to_le_bytes())?;
+    Ok(())
+}
+
+pub fn read_u16<R: Read>(reader: &mut R) -> Result<u16> {
+    let buf = &mut [0u8; 2];
+    reader.read_exact(buf)?;
+    Ok(u16::from_le_bytes(*buf))
+}
+
+pub fn write_i64<W: Write>(writer: &mut W, number: i64) -> Result<()> {
+    writer.write_all(&number.to_le_bytes())?;
+    Ok(())
+}
+
+pub fn read_i64<R: Read>(reader: &mut R) -> Result<i64> {
+    let buf = &mut [0u8; 8];
+    reader.read_exact(buf)?;
+    Ok(i64::from_le_bytes(*buf))
+}
+
+pub fn write_i32<W: Write>(writer: &mut W, number: i32) -> Result<()> {
+    writer.write_all(&number.to_le_bytes())?;
+    Ok(())
+}
+
+pub fn read_i32<R: Read>(reader: &mut R) -> Result<i32> {
+    let buf = &mut [0u8; 4];
+    reader.read_exact(buf)?;
+    Ok(i32::from_le_bytes(*buf))
+}
+
 pub fn write_u32<W: Write>(writer: &mut W, number: u32) -> Result<()> {
     writer.write_all(&number.to_le_bytes())?;
     Ok(())
@@ -101,3 +362,46 @@ pub fn write_bool<W: Write + Seek>(writer: &mut W, value: bool) -> Result<()> {
     Ok(())
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::create_dir_all, path::Path};
+
+    use anyhow::Result;
+
+    use crate::error::ArchiveError;
+
+    use super::{normalize_entry_path, resolve_extraction_path};
+
+    #[test]
+    pub fn test_normalize_entry_path() -> Result<()> {
+        assert_eq!(normalize_entry_path(Path::new("/home/me/./project"))?, Path::new("home/me/project"));
+        assert_eq!(normalize_entry_path(Path::new("test/README.md"))?, Path::new("test/README.md"));
+
+        let error = normalize_entry_path(Path::new("test/../../etc/passwd")).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ArchiveError>(), Some(ArchiveError::UnsafePath { .. })));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_refuses_symlink_escape() -> Result<()> {
+        let dest = tempfile::tempdir()?;
+        let outside = tempfile::tempdir()?;
+
+        create_dir_all(dest.path().join("inner"))?;
+        std::os::unix::fs::symlink(outside.path(), dest.path().join("escape"))?;
+        std::os::unix::fs::symlink(dest.path().join("inner"), dest.path().join("within"))?;
+
+        let error = resolve_extraction_path(dest.path(), Path::new("escape/file"), true).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ArchiveError>(), Some(ArchiveError::UnsafePath { .. })));
+
+        assert_eq!(resolve_extraction_path(dest.path(), Path::new("within/file"), true)?, dest.path().join("within/file"));
+
+        // A leaf replaces the link rather than following it.
+        assert_eq!(resolve_extraction_path(dest.path(), Path::new("escape"), true)?, dest.path().join("escape"));
+        assert!(!dest.path().join("escape").exists());
+        Ok(())
+    }
+}
@@ -2,3 +2,7 @@ pub mod ioutils;
 pub mod structure;
 pub mod security;
 pub mod constants;
+pub mod archive;
+pub mod error;
+#[cfg(test)]
+pub(crate) mod testing;
@@ -1,272 +1,622 @@
-use core::{str};
-use std::{collections::HashMap, fs::{create_dir_all, File, Metadata}, io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use std::{fs::{File, OpenOptions}, io::{BufReader, Write}, path::{Path, PathBuf}, process::ExitCode};
 
-use argon2::Argon2;
-use chacha20poly1305::{aead::{Buffer, OsRng}, AeadCore, ChaCha20Poly1305, KeyInit};
-use thunderdome::{Arena, Index};
-use walkdir::WalkDir;
 use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use zeroize::Zeroizing;
+
+use sonors::{archive::{AddOptions, Archive, ArchiveKeys, CreateOptions, ExtractOptions, Selection}, error::ArchiveError, security::{credentials::{Credentials, Factors, Keyfile}, kdf::KdfParams, recipients::{Identity, Recipient}, slots::KeySlot}, structure::{compression::{Compression, CompressionAlgorithm}, header::ArchiveHeader, metadata::Ownership, node::NodeKind}};
+
+/// The archive could not be authenticated, usually a wrong password.
+const EXIT_AUTHENTICATION: u8 = 3;
+/// The archive is damaged or has been tampered with.
+const EXIT_CORRUPT: u8 = 4;
+
+/// The environment variable a password can be supplied through instead
+/// of the interactive prompt.
+const PASSWORD_VARIABLE: &str = "SONORS_PASSWORD";
+/// The environment variable the password being added to an archive can
+/// be supplied through.
+const NEW_PASSWORD_VARIABLE: &str = "SONORS_NEW_PASSWORD";
+
+/// Encrypted, authenticated file archives.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Unlock archives with the X25519 identities in this file, may be repeated.
+    #[arg(short, long, global = true)]
+    identity: Vec<PathBuf>,
+    /// Unlock archives with this keyfile, or protect new ones with it.
+    #[arg(short, long, global = true)]
+    keyfile: Option<PathBuf>,
+    /// Refuse to derive keys with more Argon2 memory than this, 1G by default.
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    memory_limit: Option<u64>,
+    #[command(subcommand)]
+    command: Command
+}
 
-use sonors::{constants::CHUNK_SIZE, ioutils::*, security::secure::{create_key, generate_salt, read_encrypted, write_encrypted}, structure::node::ArchivalNode};
-
-use chacha20poly1305::aead::Aead;
-
-
-
-
-pub fn transfer_archival_node<R: Read + Seek, W: Write + Seek>(reader: &mut R, writer: &mut W, key: &[u8]) -> Result<()>{
+#[derive(Subcommand)]
+enum Command {
+    /// Create an archive from files and directories.
+    Create {
+        /// The archive to write.
+        archive: PathBuf,
+        #[command(flatten)]
+        input: InputArgs,
+        /// Encrypt to this X25519 recipient instead of a password, may be repeated.
+        #[arg(short, long)]
+        recipient: Vec<Recipient>,
+        /// Encrypt to every recipient in this file, may be repeated.
+        #[arg(short = 'R', long)]
+        recipients_file: Vec<PathBuf>,
+        /// Also require a password when encrypting to recipients or with a keyfile.
+        #[arg(short, long)]
+        password: bool,
+        /// Add Reed-Solomon parity taking this percentage of the archive, for `repair`.
+        #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+        recovery: Option<u8>,
+        #[command(flatten)]
+        kdf: KdfArgs
+    },
+    /// Add files and directories to an existing archive.
+    Append {
+        /// The archive to add to.
+        archive: PathBuf,
+        #[command(flatten)]
+        input: InputArgs
+    },
+    /// Remove entries from an archive, their space is freed by `compact`.
+    Remove {
+        archive: PathBuf,
+        /// The paths or glob patterns to remove, directories with their contents.
+        #[arg(required = true)]
+        patterns: Vec<String>,
+        /// Keep the entries matching this path or glob pattern, may be repeated.
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>
+    },
+    /// Rewrite an archive without the chunks of removed and replaced entries.
+    Compact {
+        archive: PathBuf
+    },
+    /// Add Reed-Solomon parity to an archive, or resize it, so damage can be repaired.
+    Protect {
+        archive: PathBuf,
+        /// The share of the archive the parity takes.
+        #[arg(long, value_name = "PERCENT", default_value_t = 5, value_parser = clap::value_parser!(u8).range(1..=100))]
+        recovery: u8
+    },
+    /// Rebuild the damaged parts of an archive from its parity, no password is needed.
+    Repair {
+        archive: PathBuf
+    },
+    /// Generate an X25519 identity and print its recipient.
+    Keygen {
+        /// Write the identity to this file instead of the standard output.
+        #[arg(short, long)]
+        output: Option<PathBuf>
+    },
+    /// List the entries of an archive.
+    List {
+        archive: PathBuf,
+        /// Show the permissions, owner and modification time of every entry.
+        #[arg(short, long)]
+        long: bool,
+        /// Print the BLAKE3 hash of every file instead, in the format `b3sum --check` reads.
+        #[arg(long, conflicts_with = "long")]
+        hashes: bool
+    },
+    /// Extract the entries of an archive, every entry by default.
+    Extract {
+        archive: PathBuf,
+        /// Extract only these paths or glob patterns, directories with their contents.
+        patterns: Vec<String>,
+        /// Skip the entries matching this path or glob pattern, may be repeated.
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+        /// The directory to extract into.
+        #[arg(short = 'C', long, default_value = ".")]
+        directory: PathBuf,
+        /// Restore the recorded owners, the default when running as root.
+        #[arg(long, conflicts_with = "no_same_owner")]
+        same_owner: bool,
+        /// Leave extracted entries owned by the current user.
+        #[arg(long)]
+        no_same_owner: bool
+    },
+    /// Decrypt and authenticate every entry without writing anything, reporting each.
+    Verify {
+        archive: PathBuf
+    },
+    /// Show the header of an archive, no password is needed.
+    Info {
+        archive: PathBuf
+    },
+    /// Change the password an archive is opened with, in place.
+    Passwd {
+        archive: PathBuf,
+        /// The Argon2 costs of the new password, those of the old one by default.
+        #[command(flatten)]
+        kdf: KdfArgs,
+        #[command(flatten)]
+        new: NewKeyArgs
+    },
+    /// Manage the passwords an archive can be opened with.
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand
+    }
+}
 
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// List the key slots, no password is needed.
+    List {
+        archive: PathBuf
+    },
+    /// Add a password, a keyfile or both in a free key slot.
+    Add {
+        archive: PathBuf,
+        #[command(flatten)]
+        kdf: KdfArgs,
+        #[command(flatten)]
+        new: NewKeyArgs
+    },
+    /// Add an X25519 recipient in a free key slot.
+    AddRecipient {
+        archive: PathBuf,
+        recipient: Recipient
+    },
+    /// Remove a key slot, the last one cannot be removed.
+    Remove {
+        archive: PathBuf,
+        /// The index of the slot, as shown by `keys list`.
+        slot: usize
+    }
+}
 
-    loop {
-        let status = read_byte(reader)?;
-        if status == 0x01 {
-            break
-        }
-       
-       // let mut buf = vec![0u8; read_u32(reader)? as usize];
-       // reader.read_exact(&mut buf)?;        
-        writer.write_all(&read_encrypted(reader, key)?)?;
-    }   
-    Ok(())
+#[derive(Args)]
+struct KdfArgs {
+    /// Argon2 memory cost in KiB.
+    #[arg(long)]
+    m_cost: Option<u32>,
+    /// Argon2 number of passes.
+    #[arg(long)]
+    t_cost: Option<u32>,
+    /// Argon2 degree of parallelism.
+    #[arg(long)]
+    p_cost: Option<u32>
 }
 
+impl KdfArgs {
+    fn params(&self) -> Result<KdfParams> {
+        self.params_or(KdfParams::default())
+    }
+    /// The parameters given, taking the rest from `defaults`.
+    fn params_or(&self, defaults: KdfParams) -> Result<KdfParams> {
+        KdfParams::new(
+            self.m_cost.unwrap_or(defaults.m_cost),
+            self.t_cost.unwrap_or(defaults.t_cost),
+            self.p_cost.unwrap_or(defaults.p_cost)
+        )
+    }
+}
 
-pub fn write_archival_node<T: Write + Seek>(writer: &mut T, node: &ArchivalNode, key: &[u8]) -> Result<u64> {
-    let pos = writer.stream_position()?;
-
-   
-    //println!("Node: {:#?}", node);
-    // Write headers
-    
-//    node.write_header_bytes(writer)?;
-    //writer.write_all(&node.get_header_bytes()?)?;
-    
-    if node.is_leaf {
-        println!("putting leaf in file...");
-        let mut reader = BufReader::new(File::open(&node.path)?);
-
-        loop {
-            let buf = &mut [0u8; CHUNK_SIZE];
-            let bytes_read = reader.read(buf)?;
-            if bytes_read == 0 {
-                break;
-            }
+#[derive(Args)]
+struct InputArgs {
+    /// The files and directories to add, stored under their own names.
+    #[arg(required_unless_present = "graft")]
+    inputs: Vec<PathBuf>,
+    /// Resolve inputs against this directory and store them relative to it.
+    #[arg(short = 'C', long)]
+    directory: Option<PathBuf>,
+    /// Drop this many leading components from every stored path.
+    #[arg(long, default_value_t = 0)]
+    strip_components: usize,
+    /// Store the inputs beneath this path.
+    #[arg(long)]
+    prefix: Option<PathBuf>,
+    /// Add SOURCE stored under its own name beneath PREFIX, may be repeated.
+    #[arg(long, value_name = "PREFIX=SOURCE", value_parser = parse_graft)]
+    graft: Vec<(PathBuf, PathBuf)>,
+    /// Compress files with none, zstd, lz4 or deflate.
+    #[arg(long, default_value = "zstd")]
+    compression: CompressionAlgorithm,
+    /// The compression level, zstd 1-22 and deflate 0-9.
+    #[arg(long, allow_negative_numbers = true)]
+    level: Option<i32>,
+    #[command(flatten)]
+    filter: FilterArgs
+}
 
-            writer.write_all(&[0x00])?;
-            write_encrypted(writer, key, &buf[..bytes_read])?;
+impl InputArgs {
+    /// Every input and graft with the options it is added with, checked
+    /// before the archive is touched.
+    fn sources(self) -> Result<Vec<(PathBuf, AddOptions)>> {
+        let compression = Compression::new(self.compression, self.level.unwrap_or(self.compression.default_level()))?;
+        let graft_options = self.filter.options(compression)?;
+        let add_options = AddOptions {
+            base: self.directory,
+            strip_components: self.strip_components,
+            prefix: self.prefix,
+            ..graft_options.clone()
+        };
+
+        let mut sources = Vec::new();
+        for input in self.inputs {
+            sources.push((input, add_options.clone()));
+        }
+        for (prefix, source) in self.graft {
+            sources.push((source, AddOptions {
+                prefix: Some(prefix),
+                ..graft_options.clone()
+            }));
         }
-        writer.write_all(&[0x01])?;
+        for (source, options) in &sources {
+            let path = options.base.as_ref().map_or_else(|| source.clone(), |base| base.join(source));
+            path.symlink_metadata().map_err(|e| anyhow!("Cannot add {path:?}: {e}"))?;
+        }
+        Ok(sources)
     }
-
-    
-    
-
-    Ok(pos)
 }
 
+#[derive(Args)]
+struct FilterArgs {
+    /// Add only the entries matching this path or glob pattern, may be repeated.
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+    /// Skip the entries matching this path or glob pattern, may be repeated.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Skip the entries listed in .gitignore and .ignore files.
+    #[arg(long)]
+    ignore_files: bool,
+    /// Skip files larger than SIZE, in bytes or with a K, M or G suffix.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Do not descend into other file systems.
+    #[arg(long)]
+    one_file_system: bool,
+    /// Follow symbolic links and add what they point to.
+    #[arg(short = 'L', long)]
+    follow_symlinks: bool
+}
 
-
-pub fn write_file_table<T: Write + Seek>(writer: &mut T, table: &SonorousFileTable, key: &[u8]) -> Result<()> {
-    let current_position = writer.stream_position()?;
-
-    let mut table_writer = Cursor::new(Vec::new());
-
-    for (key, value, node) in table.map.iter() {
-        table_writer.write_all(&key.to_le_bytes())?;
-        table_writer.write_all(&value.to_le_bytes())?;
-
-    
-
-
-
-        //let node = nodes.get(*key as usize).unwrap();
-
-        write_bool(&mut table_writer, node.is_leaf)?;
-        write_pathbuf(&mut table_writer, &node.path)?;
+impl FilterArgs {
+    /// The options adding with these filters, stored under their own names.
+    fn options(&self, compression: Compression) -> Result<AddOptions> {
+        Ok(AddOptions {
+            compression,
+            selection: Selection::new(&self.include, &self.exclude)?,
+            ignore_files: self.ignore_files,
+            max_file_size: self.max_size,
+            one_file_system: self.one_file_system,
+            follow_symlinks: self.follow_symlinks,
+            ..Default::default()
+        })
     }
+}
 
-    write_encrypted(writer, key, table_writer.into_inner().as_ref())?;
-
-
-    writer.write_all(&current_position.to_le_bytes())?;
-   
-    Ok(())
+#[derive(Args)]
+struct NewKeyArgs {
+    /// Also require this keyfile to open the new key slot.
+    #[arg(long)]
+    new_keyfile: Option<PathBuf>,
+    /// Open the new key slot with the keyfile alone.
+    #[arg(long, requires = "new_keyfile")]
+    no_new_password: bool
 }
 
-pub fn create_sonorous_file(path: impl AsRef<Path>, output: impl AsRef<Path>, password: &str) -> Result<()> {
+impl NewKeyArgs {
+    /// Reads the keyfile and prompts for the password of the new slot.
+    fn credentials(&self) -> Result<Credentials> {
+        Ok(Credentials {
+            password: if self.no_new_password { None } else { Some(new_password()?) },
+            keyfile: self.new_keyfile.as_ref().map(Keyfile::read).transpose()?,
+            ..Credentials::default()
+        })
+    }
+}
 
-    let salt = generate_salt();
-    let key = create_key(&salt, password.as_bytes())?;
-    println!("Crearef key -> {key:?}");
+/// Parses a size in bytes with an optional binary `K`, `M` or `G` suffix.
+fn parse_size(size: &str) -> Result<u64> {
+    let (digits, shift) = match size.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 10),
+        None => match size.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 20),
+            None => match size.strip_suffix(['G', 'g']) {
+                Some(digits) => (digits, 30),
+                None => (size, 0)
+            }
+        }
+    };
+    digits.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or_else(|| anyhow!("Expected a size such as 4096, 512K or 10M but found {size:?}."))
+}
 
-    let mut node_list = Vec::new();
+/// Parses a `PREFIX=SOURCE` graft point.
+fn parse_graft(graft: &str) -> Result<(PathBuf, PathBuf)> {
+    let (prefix, source) = graft.split_once('=')
+        .ok_or_else(|| anyhow!("Expected PREFIX=SOURCE but found {graft:?}."))?;
+    Ok((prefix.into(), source.into()))
+}
 
-    for entry in WalkDir::new(path.as_ref()) {
-        let entry = entry?;
-        node_list.push(ArchivalNode {
-            path: entry.path().to_path_buf(),
-            is_leaf: !entry.path().is_dir(),
-            //metadata: entry.metadata()?
+/// Formats permission bits like `ls -l`, e.g. `drwxr-xr-x`.
+fn format_mode(mode: u32, kind: &NodeKind) -> String {
+    let mut formatted = String::from(match kind {
+        NodeKind::File => '-',
+        NodeKind::Directory => 'd',
+        NodeKind::Symlink { .. } => 'l',
+        NodeKind::Hardlink { .. } => 'h'
+    });
+    for (shift, special, set, unset) in [(6, 0o4000, 's', 'S'), (3, 0o2000, 's', 'S'), (0, 0o1000, 't', 'T')] {
+        let bits = mode >> shift;
+        formatted.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        formatted.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        formatted.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => set,
+            (false, true) => unset,
+            (true, false) => 'x',
+            (false, false) => '-'
         });
     }
+    formatted
+}
 
-    let mut file_table = SonorousFileTable::default();
+/// Formats seconds since the Unix epoch as a UTC `YYYY-MM-DD HH:MM` date.
+fn format_timestamp(seconds: i64) -> String {
+    let (days, time) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+
+    // Converts days since the epoch to a civil date (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}", time / 3600, time % 3600 / 60)
+}
 
-//    let mut file_table: HashMap<u32, u64> = HashMap::new();
+/// Reads the password from the environment or prompts for it without
+/// echoing, asking twice when `confirm` is set.
+fn password(confirm: bool) -> Result<Zeroizing<String>> {
+    read_password(PASSWORD_VARIABLE, "Password", confirm)
+}
 
-    let mut file_writer = BufWriter::new(File::create(output.as_ref())?);
-    file_writer.write_all(&salt)?;
+/// Reads a password being added to an archive, always confirming it.
+fn new_password() -> Result<Zeroizing<String>> {
+    read_password(NEW_PASSWORD_VARIABLE, "New password", true)
+}
 
-    for (index, node) in node_list.into_iter().enumerate() {
-        file_table.map.push((index.try_into()?, write_archival_node(&mut file_writer, &node, &key)?, node));
+fn read_password(variable: &str, prompt: &str, confirm: bool) -> Result<Zeroizing<String>> {
+    if let Ok(password) = std::env::var(variable) {
+        return Ok(Zeroizing::new(password));
     }
-
-    write_file_table(&mut file_writer, &file_table, &key)?;
-
-
-    Ok(())
+    let password = Zeroizing::new(rpassword::prompt_password(format!("{prompt}: "))?);
+    if confirm && *Zeroizing::new(rpassword::prompt_password(format!("Confirm {}: ", prompt.to_lowercase()))?) != *password {
+        return Err(anyhow!("The passwords do not match."));
+    }
+    Ok(password)
 }
 
-#[derive(Default, Debug)]
-pub struct SonorousFileTable {
-    map: Vec<(u32, u64, ArchivalNode)>,
-    key: Vec<u8>
+/// Prints the occupied key slots with their index.
+fn print_slots(slots: &[KeySlot]) {
+    for (index, slot) in slots.iter().enumerate().filter(|(_, slot)| !slot.is_empty()) {
+        println!("Key slot {index}:     {slot}");
+    }
 }
 
-
-impl SonorousFileTable {
-    pub fn new(key: &[u8]) -> Self {
-        Self {
-            map: Vec::default(),
-            key: key.to_vec()
-        }
+/// Reads the credentials for opening `archive`: the identities in
+/// `identity_files` and the keyfile, if given, and the password. The
+/// password is only prompted for when no key slot of the archive opens
+/// without one.
+fn credentials(archive: &Path, identity_files: &[PathBuf], keyfile: &Option<PathBuf>, memory_limit: Option<u64>) -> Result<Credentials> {
+    let mut credentials = Credentials {
+        password: std::env::var(PASSWORD_VARIABLE).ok().map(Zeroizing::new),
+        keyfile: keyfile.as_ref().map(Keyfile::read).transpose()?,
+        ..Credentials::default()
+    };
+    if let Some(limit) = memory_limit {
+        credentials.memory_limit = (limit / 1024).try_into().unwrap_or(u32::MAX);
     }
-    pub fn from_reader<T: Read + Seek>(reader: &mut T, password: &str) -> Result<Self> {
-        read_sonorous_file_table(reader, password)
+    for file in identity_files {
+        credentials.identities.extend(Identity::from_file(file)?);
     }
-    pub fn files(&self) -> Vec<&PathBuf> {
-        self.map.iter().map(|(_, _, node)| &node.path).collect::<Vec<&PathBuf>>()
-    }
-    pub fn expand_into_files<T: Read + Seek>(&self, reader: &mut T, dest: impl AsRef<Path>) -> Result<()> {
-        for (key, position, node) in &self.map {
-            reader.seek(SeekFrom::Start(*position))?;
-
-
-            // Create the directory tree if it does not exist.
-            let path = dest.as_ref().join(&node.path);
-            println!("Creating directory.");
-            create_directory_tree(&path, node.is_leaf)?;
-        
-            println!("done");
-            if node.is_leaf {
-                println!("Writing a leaf node...");
-                let writer = &mut BufWriter::new(File::create(&path)?);
-                println!("Created writer...");
-                transfer_archival_node(reader, writer, &self.key)?;
-            }
-        }
 
-        Ok(())
+    let header = ArchiveHeader::read(&mut BufReader::new(File::open(archive)?))?;
+    let without_password = header.key_slots.iter().any(|slot| match slot {
+        KeySlot::X25519 { .. } => !credentials.identities.is_empty(),
+        _ => slot.factors() == Some(Factors::Keyfile) && credentials.keyfile.is_some()
+    });
+    if credentials.password.is_none() && !without_password {
+        credentials.password = Some(password(false)?);
     }
+    Ok(credentials)
 }
 
+fn run(cli: Cli) -> Result<()> {
+    let (identity, keyfile, memory_limit) = (cli.identity, cli.keyfile, cli.memory_limit);
+    match cli.command {
+        Command::Create { archive, input, mut recipient, recipients_file, password: with_password, recovery, kdf } => {
+            for file in recipients_file {
+                recipient.extend(Recipient::from_file(file)?);
+            }
+            let with_password = with_password || (recipient.is_empty() && keyfile.is_none());
+            let credentials = Credentials {
+                password: if with_password { Some(password(true)?) } else { None },
+                keyfile: keyfile.map(Keyfile::read).transpose()?,
+                ..Credentials::default()
+            };
+            let options = CreateOptions {
+                kdf_params: kdf.params()?,
+                recipients: recipient
+            };
+            let sources = input.sources()?;
+            let mut writer = Archive::create_with(&archive, &credentials, options)?;
+
+            for (source, options) in sources {
+                writer.add_path_with(source, &options)?;
+            }
+            writer.finish()?;
+            if let Some(percent) = recovery {
+                Archive::protect(&archive, percent)?;
+            }
+        }
+        Command::Append { archive, input } => {
+            let sources = input.sources()?;
+            let mut writer = Archive::append_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?;
 
-
-pub fn read_sonorous_file_table<T: Read + Seek>(reader: &mut T, password: &str) -> Result<SonorousFileTable> {
-
-    reader.seek(SeekFrom::Start(0))?;
-    
-    let salt = &mut [0u8; 32];
-    reader.read_exact(salt)?;
-
-    let key = &create_key(salt, password.as_bytes())?;
-
-    println!("Created key -> {key:?}");
-
-
-    reader.seek(SeekFrom::End(-8))?;
-    let stop_position = reader.stream_position()?;
-
-    let table_position = read_u64(reader)?;
-    //println!("Table position: {table_position}");
-    reader.seek(SeekFrom::Start(table_position))?;
-
-
-    let decrypted = read_encrypted(reader, key)?;
-    
-    let decrypted_len = decrypted.len();
-    let mut reader = Cursor::new(Vec::from(decrypted));
-
-
-
-    let mut file_table = SonorousFileTable::new(key);
-
-
-    loop {
-
-        let key = read_u32(&mut reader)?;
-        let value = read_u64(&mut reader)?;
-        let is_leaf = read_bool(&mut reader)?;
-        let path = read_pathbuf(&mut reader)?;
-       
-
-        println!("Found some data...");
-
-        //println!("({key}, {value}) -> {path:?}");
-    
-        file_table.map.push((key, value, ArchivalNode {
-            is_leaf,
-            path
-        }));
-
-
-        if reader.stream_position()? == decrypted_len as u64 {
-            break
+            for (source, options) in sources {
+                writer.add_path_with(source, &options)?;
+            }
+            writer.finish()?;
+        }
+        Command::Remove { archive, patterns, exclude } => {
+            let selection = Selection::new(&patterns, &exclude)?;
+            let mut writer = Archive::append_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?;
+            let removed = writer.remove(&selection)?;
+            writer.finish()?;
+            println!("Removed {} entries, run `compact` to reclaim their space.", removed.len());
+        }
+        Command::Compact { archive } => {
+            let compaction = Archive::compact(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?;
+            println!("Reclaimed {} bytes, the archive now takes {} bytes.", compaction.reclaimed(), compaction.after);
+        }
+        Command::Protect { archive, recovery } => {
+            let record = Archive::protect(&archive, recovery)?;
+            println!("Added {} parity shards of {} bytes, as many damaged blocks per stripe can be repaired.", record.parity_shards, record.shard_size);
+        }
+        Command::Repair { archive } => {
+            let repair = Archive::repair(&archive)?;
+            if repair.is_empty() {
+                println!("{}: no damage found.", archive.display());
+            } else {
+                println!("Rebuilt {} damaged blocks of the archive and {} of the recovery record.", repair.data_blocks, repair.parity_blocks);
+            }
+        }
+        Command::List { archive, long, hashes } => {
+            let reader = Archive::open_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?;
+            if hashes {
+                for (_, _, node) in reader.entries() {
+                    if let Some(hash) = node.hash {
+                        println!("{}  {}", blake3::Hash::from_bytes(hash).to_hex(), node.path.display());
+                    }
+                }
+                return Ok(());
+            }
+            for (_, _, node) in reader.entries() {
+                let link = match &node.kind {
+                    NodeKind::Symlink { target } => format!(" -> {}", target.display()),
+                    NodeKind::Hardlink { target } => format!(" link to {}", target.display()),
+                    _ => String::new()
+                };
+                match (&node.metadata, long) {
+                    (Some(metadata), true) => println!("{} {:>8}/{:<8} {} {}{link}",
+                        format_mode(metadata.mode, &node.kind),
+                        metadata.user.clone().unwrap_or_else(|| metadata.uid.to_string()),
+                        metadata.group.clone().unwrap_or_else(|| metadata.gid.to_string()),
+                        format_timestamp(metadata.mtime.seconds),
+                        node.path.display()
+                    ),
+                    _ => println!("{}{link}", node.path.display())
+                }
+            }
+        }
+        Command::Extract { archive, patterns, exclude, directory, same_owner, no_same_owner } => {
+            let options = ExtractOptions {
+                ownership: match (same_owner, no_same_owner) {
+                    (true, _) => Ownership::Restore,
+                    (_, true) => Ownership::Skip,
+                    _ => Ownership::Auto
+                },
+                selection: Selection::new(&patterns, &exclude)?
+            };
+            Archive::open_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?.extract_all_with(directory, &options)?;
+        }
+        Command::Verify { archive } => {
+            let report = Archive::open_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?.verify()?;
+            for entry in &report.entries {
+                println!("{}: {}", entry.path.display(), entry.status);
+            }
+            let damaged = report.damaged().count();
+            if damaged > 0 {
+                Err(ArchiveError::DamagedEntries { count: damaged })?
+            }
+            println!("{}: OK", archive.display());
+        }
+        Command::Info { archive } => {
+            let header = ArchiveHeader::read(&mut BufReader::new(File::open(&archive)?))?;
+            println!("Format version: {}", header.version);
+            println!("Cipher:         {:?}", header.cipher);
+            println!("Key derivation: {:?}", header.kdf);
+            println!("Flags:          0x{:08x}", header.flags);
+            if !header.is_legacy() {
+                println!("Archive id:     {}", header.archive_id.iter().map(|b| format!("{b:02x}")).collect::<String>());
+            }
+            match Archive::recovery_record(&archive)? {
+                Some(record) => println!("Recovery:       {}%, {} data and {} parity shards of {} bytes",
+                    record.percent, record.data_shards, record.parity_shards, record.shard_size),
+                None => println!("Recovery:       none")
+            }
+            print_slots(&header.key_slots);
+        }
+        Command::Keygen { output } => {
+            let generated = Identity::generate();
+            match output {
+                Some(output) => {
+                    let mut file = OpenOptions::new().write(true).create_new(true).open(&output)?;
+                    #[cfg(unix)]
+                    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+                    file.write_all(generated.to_file_contents().as_bytes())?;
+                    eprintln!("Public key: {}", generated.recipient());
+                }
+                None => print!("{}", generated.to_file_contents())
+            }
+        }
+        Command::Passwd { archive, kdf, new } => {
+            let mut keys = ArchiveKeys::open_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?;
+            let current = keys.slots()[keys.unlocked_slot()].kdf_params().copied().unwrap_or_default();
+            let index = keys.change_credentials(&new.credentials()?, &kdf.params_or(current)?)?;
+            println!("Password changed, now in key slot {index}.");
+        }
+        Command::Keys { command: KeysCommand::List { archive } } => {
+            print_slots(&ArchiveHeader::read(&mut BufReader::new(File::open(&archive)?))?.key_slots);
+        }
+        Command::Keys { command: KeysCommand::Add { archive, kdf, new } } => {
+            let kdf_params = kdf.params()?;
+            let mut keys = ArchiveKeys::open_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?;
+            let index = keys.add_credentials(&new.credentials()?, &kdf_params)?;
+            println!("Added key slot {index}.");
+        }
+        Command::Keys { command: KeysCommand::AddRecipient { archive, recipient } } => {
+            let index = ArchiveKeys::open_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?.add_recipient(&recipient)?;
+            println!("Added key slot {index}.");
+        }
+        Command::Keys { command: KeysCommand::Remove { archive, slot } } => {
+            ArchiveKeys::open_with(&archive, &credentials(&archive, &identity, &keyfile, memory_limit)?)?.remove_slot(slot)?;
+            println!("Removed key slot {slot}.");
         }
-        //if reader.stream_position()? == stop_position {
-        //    break
-        //}
     }
-    println!("Done reading... {file_table:?}");
-
-    Ok(file_table)
+    Ok(())
 }
 
-//pub enum SonorousHeader {
-//    PasswordSalt = 0x00,
-//    UtilityVersion = 0x01
-//}
-
-
-
-
-fn main() -> Result<()> {
-   
-    
-  
-    //let (salt, key) = create_key("hello".as_bytes().as_ref())?;
-    //println!("key: {:?}", key);
-
-
-
-
- //   println!("My borhter {:?}", key);
-
-
- //    println!("Plan: {:?}", std::str::from_utf8(&plaintext)); 
-   
-    println!("Creating file.");
-    create_sonorous_file("test", "archive.srs", "hello")?;
-    println!("File created.");
-
-
-    let mut reader = BufReader::new(File::open("archive.srs")?);
-    let file_table = SonorousFileTable::from_reader(&mut reader, "hello")?;
-    file_table.expand_into_files(&mut reader, "wowz")?;
-    
-
-    //create_sonorous_file(path, "archive.srs")?;
-
-
-    Ok(())
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("sonors: {e:#}");
+            match e.downcast_ref::<ArchiveError>() {
+                Some(ArchiveError::Authentication) => ExitCode::from(EXIT_AUTHENTICATION),
+                Some(_) => ExitCode::from(EXIT_CORRUPT),
+                None => ExitCode::FAILURE
+            }
+        }
+    }
 }
@@ -0,0 +1,133 @@
+use std::{fmt, fs::File, io::copy, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use zeroize::Zeroizing;
+
+use super::{kdf::DEFAULT_MEMORY_LIMIT, recipients::Identity, secret::SecretKey};
+
+/// The context keyfiles are hashed with.
+const KEYFILE_CONTEXT: &str = "sonors 2024-08 keyfile";
+
+/// What an archive is unlocked with.
+///
+/// Every key slot is tried with whichever of these applies to it, so an
+/// archive with both password and recipient slots opens with either.
+#[derive(Clone)]
+pub struct Credentials {
+    /// Wiped when dropped, like every other secret.
+    pub password: Option<Zeroizing<String>>,
+    pub keyfile: Option<Keyfile>,
+    pub identities: Vec<Identity>,
+    /// The largest Argon2 memory cost in KiB a key slot may ask for.
+    /// Unlocking stops at the first slot asking for more, instead of
+    /// deriving its key.
+    pub memory_limit: u32
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self {
+            password: None,
+            keyfile: None,
+            identities: Vec::new(),
+            memory_limit: DEFAULT_MEMORY_LIMIT
+        }
+    }
+}
+
+impl Credentials {
+    /// Unlocks with a password alone.
+    pub fn password(password: &str) -> Self {
+        Self {
+            password: Some(Zeroizing::new(password.to_string())),
+            ..Self::default()
+        }
+    }
+    /// Unlocks with X25519 identities alone.
+    pub fn identities(identities: Vec<Identity>) -> Self {
+        Self {
+            identities,
+            ..Self::default()
+        }
+    }
+}
+
+/// A file whose contents act as a secret, kept only as a hash of them.
+///
+/// Any file will do, though one of random bytes is best. Changing a
+/// single byte of it makes it a different keyfile.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keyfile(SecretKey);
+
+impl Keyfile {
+    /// Hashes the contents of the file at `path`.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path).with_context(|| format!("Failed to open the keyfile {path:?}."))?;
+        let mut hasher = blake3::Hasher::new_derive_key(KEYFILE_CONTEXT);
+        copy(&mut file, &mut hasher)?;
+        Ok(Self(SecretKey::from_fn(|key| hasher.finalize_xof().fill(key))))
+    }
+    /// Hashes keyfile contents already in memory.
+    pub fn from_contents(contents: &[u8]) -> Self {
+        let mut hasher = blake3::Hasher::new_derive_key(KEYFILE_CONTEXT);
+        hasher.update(contents);
+        Self(SecretKey::from_fn(|key| hasher.finalize_xof().fill(key)))
+    }
+}
+
+/// The secrets the key of a password slot is derived from, recorded in
+/// the slot so readers know what to ask for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Factors {
+    Password = 0x00,
+    Keyfile = 0x01,
+    /// Both, so a weak password alone is not enough.
+    PasswordAndKeyfile = 0x02
+}
+
+impl Factors {
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0x00 => Ok(Self::Password),
+            0x01 => Ok(Self::Keyfile),
+            0x02 => Ok(Self::PasswordAndKeyfile),
+            _ => Err(anyhow!("Unknown key slot factors 0x{byte:02x} in the archive header."))
+        }
+    }
+    /// The factors `credentials` provide, if any.
+    pub fn of(credentials: &Credentials) -> Option<Self> {
+        match (&credentials.password, &credentials.keyfile) {
+            (Some(_), None) => Some(Self::Password),
+            (None, Some(_)) => Some(Self::Keyfile),
+            (Some(_), Some(_)) => Some(Self::PasswordAndKeyfile),
+            (None, None) => None
+        }
+    }
+    /// Whether a password is among the factors.
+    pub fn needs_password(&self) -> bool {
+        *self != Self::Keyfile
+    }
+    /// The input to the key derivation, the keyfile hash followed by the
+    /// password bytes, or `None` if `credentials` lack a factor.
+    pub fn material(&self, credentials: &Credentials) -> Option<Zeroizing<Vec<u8>>> {
+        let password = credentials.password.as_ref().map(|password| password.as_bytes());
+        let keyfile = credentials.keyfile.as_ref().map(|keyfile| &keyfile.0[..]);
+        match self {
+            Self::Password => Some(Zeroizing::new(password?.to_vec())),
+            Self::Keyfile => Some(Zeroizing::new(keyfile?.to_vec())),
+            Self::PasswordAndKeyfile => Some(Zeroizing::new([keyfile?, password?].concat()))
+        }
+    }
+}
+
+impl fmt::Display for Factors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Password => "password",
+            Self::Keyfile => "keyfile",
+            Self::PasswordAndKeyfile => "password and keyfile"
+        })
+    }
+}
@@ -0,0 +1,172 @@
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::{constants::KEY_LENGTH_IN_BYTES, ioutils::{read_u32, write_u32}};
+
+/// The largest memory cost accepted from an archive header, in KiB (4 GiB).
+///
+/// Guards against a crafted header making the reader allocate an
+/// unbounded amount of memory before the password is even checked.
+pub const MAX_MEMORY_COST: u32 = 4 * 1024 * 1024;
+
+/// The largest memory cost readers derive a key with unless told
+/// otherwise through [Credentials](super::credentials::Credentials), in
+/// KiB (1 GiB).
+///
+/// A header is not authenticated before its slots are tried, so this is
+/// all that stops a crafted one from making every open take minutes.
+pub const DEFAULT_MEMORY_LIMIT: u32 = 1024 * 1024;
+
+/// The largest number of passes accepted from an archive header.
+///
+/// Like [MAX_MEMORY_COST], keeps a crafted header from making the reader
+/// spend unbounded time on every key slot before anything is
+/// authenticated.
+pub const MAX_TIME_COST: u32 = 64;
+
+/// The largest degree of parallelism accepted from an archive header.
+pub const MAX_PARALLELISM: u32 = 64;
+
+/// The Argon2 parameters used to derive a key from a password.
+///
+/// These are recorded in the archive so that a change to the defaults
+/// of the `argon2` crate never makes an existing archive undecryptable.
+///
+/// Serialized as:
+///
+/// [ u8 variant ] [ u32 version ] [ u32 m_cost ] [ u32 t_cost ] [ u32 p_cost ]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: Algorithm,
+    pub version: Version,
+    /// Memory cost in KiB.
+    pub m_cost: u32,
+    /// Number of passes.
+    pub t_cost: u32,
+    /// Degree of parallelism.
+    pub p_cost: u32
+}
+
+impl Default for KdfParams {
+    /// Pinned to the values `argon2` 0.5 used as its defaults, which is
+    /// also what every legacy archive was written with.
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Argon2id,
+            version: Version::V0x13,
+            m_cost: 19 * 1024,
+            t_cost: 2,
+            p_cost: 1
+        }
+    }
+}
+
+impl KdfParams {
+    /// Creates a new set of Argon2id parameters, validating the costs.
+    pub fn new(m_cost: u32, t_cost: u32, p_cost: u32) -> Result<Self> {
+        let params = Self {
+            m_cost,
+            t_cost,
+            p_cost,
+            ..Self::default()
+        };
+        params.validate()?;
+        Ok(params)
+    }
+    /// Checks that the parameters are accepted by Argon2 and within the
+    /// limits this build is willing to use.
+    pub fn validate(&self) -> Result<()> {
+        if self.m_cost > MAX_MEMORY_COST {
+            return Err(anyhow!("Argon2 memory cost of {} KiB exceeds the limit of {MAX_MEMORY_COST} KiB.", self.m_cost));
+        }
+        if self.t_cost > MAX_TIME_COST {
+            return Err(anyhow!("Argon2 time cost of {} passes exceeds the limit of {MAX_TIME_COST}.", self.t_cost));
+        }
+        if self.p_cost > MAX_PARALLELISM {
+            return Err(anyhow!("Argon2 parallelism of {} lanes exceeds the limit of {MAX_PARALLELISM}.", self.p_cost));
+        }
+        self.argon2_params()?;
+        Ok(())
+    }
+    /// Builds the [Argon2] hasher described by these parameters.
+    pub fn hasher(&self) -> Result<Argon2<'static>> {
+        Ok(Argon2::new(self.algorithm, self.version, self.argon2_params()?))
+    }
+    fn argon2_params(&self) -> Result<Params> {
+        Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LENGTH_IN_BYTES))
+            .map_err(|e| anyhow!("Invalid Argon2 parameters (m={}, t={}, p={}): {e}", self.m_cost, self.t_cost, self.p_cost))
+    }
+    /// Writes the parameters to a [Writer](std::io).
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let algorithm = match self.algorithm {
+            Algorithm::Argon2d => 0x00,
+            Algorithm::Argon2i => 0x01,
+            Algorithm::Argon2id => 0x02
+        };
+        writer.write_all(&[algorithm])?;
+        write_u32(writer, self.version as u32)?;
+        write_u32(writer, self.m_cost)?;
+        write_u32(writer, self.t_cost)?;
+        write_u32(writer, self.p_cost)?;
+        Ok(())
+    }
+    /// Reads and validates parameters written by [KdfParams::write].
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let algorithm = &mut [0u8; 1];
+        reader.read_exact(algorithm)?;
+        let algorithm = match algorithm[0] {
+            0x00 => Algorithm::Argon2d,
+            0x01 => Algorithm::Argon2i,
+            0x02 => Algorithm::Argon2id,
+            other => Err(anyhow!("Unknown Argon2 variant 0x{other:02x}."))?
+        };
+        let version = Version::try_from(read_u32(reader)?)
+            .map_err(|e| anyhow!("Unknown Argon2 version: {e}"))?;
+
+        let params = Self {
+            algorithm,
+            version,
+            m_cost: read_u32(reader)?,
+            t_cost: read_u32(reader)?,
+            p_cost: read_u32(reader)?
+        };
+        params.validate()?;
+        Ok(params)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+    use argon2::Algorithm;
+
+    use super::KdfParams;
+
+    #[test]
+    pub fn test_params_roundtrip() -> Result<()> {
+        let params = KdfParams {
+            algorithm: Algorithm::Argon2i,
+            ..KdfParams::new(4096, 3, 2)?
+        };
+
+        let mut export = Cursor::new(Vec::new());
+        params.write(&mut export)?;
+        export.set_position(0);
+
+        assert_eq!(KdfParams::read(&mut export)?, params);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_rejects_invalid_params() {
+        assert!(KdfParams::new(1, 0, 1).is_err());
+        assert!(KdfParams::new(u32::MAX, 2, 1).is_err());
+        assert!(KdfParams::new(1024, u32::MAX, 1).is_err());
+        assert!(KdfParams::new(1024, 2, u32::MAX).is_err());
+    }
+}
@@ -1 +1,6 @@
 pub mod secure;
+pub mod secret;
+pub mod kdf;
+pub mod slots;
+pub mod recipients;
+pub mod credentials;
@@ -0,0 +1,186 @@
+use std::{fmt, fs::read_to_string, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use super::secret::SecretKey;
+
+/// The prefix of an encoded [Recipient].
+const RECIPIENT_PREFIX: &str = "sonors1";
+/// The prefix of an encoded [Identity].
+const IDENTITY_PREFIX: &str = "SONORS-SECRET-KEY-1";
+
+/// The context the key wrapping an X25519 key slot is derived with.
+const WRAPPING_KEY_CONTEXT: &str = "sonors 2024-08 x25519 key slot";
+
+/// An X25519 public key archives can be encrypted to, written as
+/// `sonors1` followed by 64 lowercase hex digits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+/// An X25519 secret key opening archives encrypted to its [Recipient],
+/// written as `SONORS-SECRET-KEY-1` followed by 64 uppercase hex digits.
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Recipient {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+    /// Reads a recipients file, one recipient per line. Blank lines and
+    /// lines starting with `#` are ignored.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let path = path.as_ref();
+        non_comment_lines(&read_to_string(path)?)
+            .map(str::parse)
+            .collect::<Result<_>>()
+            .with_context(|| format!("Failed to read the recipients file {path:?}."))
+    }
+    /// Derives a fresh key to wrap an archive key for this recipient,
+    /// returning it with the ephemeral public key the recipient needs to
+    /// derive it again.
+    pub fn wrapping_key(&self) -> Result<(SecretKey, [u8; 32])> {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let key = wrapping_key(&ephemeral, &self.0, ephemeral_public.as_bytes(), self.as_bytes())?;
+        Ok((key, ephemeral_public.to_bytes()))
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = anyhow::Error;
+
+    fn from_str(encoded: &str) -> Result<Self> {
+        let hex = encoded.strip_prefix(RECIPIENT_PREFIX)
+            .ok_or_else(|| anyhow!("{encoded:?} is not a recipient, recipients start with {RECIPIENT_PREFIX}."))?;
+        Ok(Self(PublicKey::from(decode_key(hex)?)))
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{RECIPIENT_PREFIX}{}", encode_key(self.as_bytes()).to_lowercase())
+    }
+}
+
+impl Identity {
+    /// Generates a new random identity.
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+    /// The recipient archives are encrypted to for this identity.
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+    /// Reads an identity file, one identity per line. Blank lines and
+    /// lines starting with `#` are ignored.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let path = path.as_ref();
+        let contents = Zeroizing::new(read_to_string(path)?);
+        non_comment_lines(&contents)
+            .map(str::parse)
+            .collect::<Result<_>>()
+            .with_context(|| format!("Failed to read the identity file {path:?}."))
+    }
+    /// The contents of an identity file holding this identity.
+    pub fn to_file_contents(&self) -> String {
+        format!("# public key: {}\n{self}\n", self.recipient())
+    }
+    /// Derives the key an archive key was wrapped with for this
+    /// identity, given the ephemeral public key stored alongside it.
+    pub fn wrapping_key(&self, ephemeral_public: &[u8; 32]) -> Result<SecretKey> {
+        wrapping_key(&self.0, &PublicKey::from(*ephemeral_public), ephemeral_public, self.recipient().as_bytes())
+    }
+}
+
+impl FromStr for Identity {
+    type Err = anyhow::Error;
+
+    fn from_str(encoded: &str) -> Result<Self> {
+        let hex = encoded.strip_prefix(IDENTITY_PREFIX)
+            .ok_or_else(|| anyhow!("Not an identity, identities start with {IDENTITY_PREFIX}."))?;
+        let key = Zeroizing::new(decode_key(hex)?);
+        Ok(Self(StaticSecret::from(*key)))
+    }
+}
+
+/// Writes the encoded secret key, take care where it goes.
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{IDENTITY_PREFIX}{}", encode_key(self.0.as_bytes()))
+    }
+}
+
+/// Derives a wrapping key from the X25519 shared secret, bound to both
+/// public keys. Refuses low order points, which would make the shared
+/// secret predictable.
+fn wrapping_key(secret: &StaticSecret, public: &PublicKey, ephemeral_public: &[u8; 32], recipient: &[u8; 32]) -> Result<SecretKey> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(anyhow!("Refusing a low order X25519 public key."));
+    }
+    let material = Zeroizing::new([shared.as_bytes().as_slice(), ephemeral_public, recipient].concat());
+    let mut hasher = blake3::Hasher::new_derive_key(WRAPPING_KEY_CONTEXT);
+    hasher.update(&material);
+    Ok(SecretKey::from_fn(|key| hasher.finalize_xof().fill(key)))
+}
+
+fn non_comment_lines(contents: &str) -> impl Iterator<Item = &str> {
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn encode_key(key: &[u8; 32]) -> String {
+    key.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+fn decode_key(hex: &str) -> Result<[u8; 32]> {
+    // Checked up front, `from_str_radix` also takes a leading sign.
+    if hex.len() != 64 || !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return Err(anyhow!("Expected a key of 64 hex digits."));
+    }
+    let mut key = [0u8; 32];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)
+            .map_err(|_| anyhow!("Expected a key of 64 hex digits."))?;
+    }
+    Ok(key)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{Identity, Recipient, RECIPIENT_PREFIX};
+
+    #[test]
+    pub fn test_encoding() -> Result<()> {
+        let identity = Identity::generate();
+        let recipient = identity.recipient();
+
+        assert_eq!(recipient.to_string().parse::<Recipient>()?, recipient);
+        assert_eq!(identity.to_string().parse::<Identity>()?.recipient(), recipient);
+        assert!("sonors1zz".parse::<Recipient>().is_err());
+        // A digit pair with a sign in place of its first digit.
+        let signed = format!("{RECIPIENT_PREFIX}+f{}", &recipient.to_string()[RECIPIENT_PREFIX.len() + 2..]);
+        assert!(signed.parse::<Recipient>().is_err());
+        assert!(identity.to_string().parse::<Recipient>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_wrapping_key() -> Result<()> {
+        let identity = Identity::generate();
+        let (key, ephemeral_public) = identity.recipient().wrapping_key()?;
+        assert_eq!(identity.wrapping_key(&ephemeral_public)?, key);
+        assert_ne!(Identity::generate().wrapping_key(&ephemeral_public)?, key);
+
+        // The all zero point is of low order.
+        assert!(identity.wrapping_key(&[0u8; 32]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use std::{fmt, ops::Deref};
+
+use anyhow::{anyhow, Result};
+use zeroize::Zeroize;
+
+use crate::constants::KEY_LENGTH_IN_BYTES;
+
+/// A key that is wiped from memory when dropped.
+///
+/// The bytes live on the heap so they are never copied when the key is
+/// moved, and with the `mlock` feature on Unix their page is locked so
+/// it is not swapped to disk. Locking is best effort, it fails silently
+/// when the memory lock limit is reached.
+///
+/// [fmt::Debug] never shows the bytes and equality is checked in
+/// constant time.
+pub struct SecretKey {
+    bytes: Box<[u8; KEY_LENGTH_IN_BYTES]>,
+    #[cfg_attr(not(all(unix, feature = "mlock")), allow(dead_code))]
+    locked: bool
+}
+
+impl SecretKey {
+    /// Copies `bytes` into a new key, wiping them.
+    pub fn new(bytes: &mut [u8; KEY_LENGTH_IN_BYTES]) -> Self {
+        let key = Self::from_slice(bytes.as_slice()).expect("the lengths match");
+        bytes.zeroize();
+        key
+    }
+    /// Creates a key whose bytes `fill` writes in place, so they are
+    /// never held anywhere else.
+    pub fn from_fn(fill: impl FnOnce(&mut [u8; KEY_LENGTH_IN_BYTES])) -> Self {
+        let mut key = Self::zeroed();
+        fill(&mut key.bytes);
+        key
+    }
+    /// Copies a key out of `bytes`, which must be exactly
+    /// [KEY_LENGTH_IN_BYTES] long. Wiping `bytes` is left to the caller.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != KEY_LENGTH_IN_BYTES {
+            return Err(anyhow!("Expected a key of {KEY_LENGTH_IN_BYTES} bytes but found {}.", bytes.len()));
+        }
+        let mut key = Self::zeroed();
+        key.bytes.copy_from_slice(bytes);
+        Ok(key)
+    }
+    /// A locked key of zeros, to be written over.
+    fn zeroed() -> Self {
+        let mut key = Self {
+            bytes: Box::new([0u8; KEY_LENGTH_IN_BYTES]),
+            locked: false
+        };
+        key.lock();
+        key
+    }
+    pub fn as_bytes(&self) -> &[u8; KEY_LENGTH_IN_BYTES] {
+        &self.bytes
+    }
+    fn lock(&mut self) {
+        #[cfg(all(unix, feature = "mlock"))]
+        {
+            let address = std::ptr::NonNull::from(self.bytes.as_mut()).cast();
+            // SAFETY: the range is the boxed key, which outlives the lock.
+            self.locked = unsafe { nix::sys::mman::mlock(address, KEY_LENGTH_IN_BYTES) }.is_ok();
+        }
+    }
+    fn unlock(&mut self) {
+        #[cfg(all(unix, feature = "mlock"))]
+        if self.locked {
+            let address = std::ptr::NonNull::from(self.bytes.as_mut()).cast();
+            // SAFETY: the range was locked by `lock` and is still allocated.
+            let _ = unsafe { nix::sys::mman::munlock(address, KEY_LENGTH_IN_BYTES) };
+        }
+    }
+}
+
+impl Clone for SecretKey {
+    fn clone(&self) -> Self {
+        Self::from_slice(self.bytes.as_slice()).expect("the lengths match")
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+        self.unlock();
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+}
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes.iter().zip(other.bytes.iter()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+}
+
+impl Eq for SecretKey {}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::SecretKey;
+
+    #[test]
+    pub fn test_secret_key() {
+        let mut bytes = [7u8; 32];
+        let key = SecretKey::new(&mut bytes);
+        assert_eq!(bytes, [0u8; 32]);
+        assert_eq!(*key, [7u8; 32]);
+        assert_eq!(key.clone(), key);
+        assert_eq!(SecretKey::from_fn(|bytes| bytes.fill(7)), key);
+        assert_ne!(SecretKey::new(&mut [8u8; 32]), key);
+        assert!(!format!("{key:?}").contains('7'));
+        assert!(SecretKey::from_slice(&[0u8; 16]).is_err());
+    }
+}
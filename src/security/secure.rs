@@ -1,9 +1,16 @@
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use anyhow::{anyhow, Result};
-use argon2::{password_hash::rand_core::RngCore, Argon2};
-use chacha20poly1305::{aead::{Aead, OsRng}, AeadCore, ChaCha20Poly1305, KeyInit};
-use crate::{constants::{KEY_LENGTH_IN_BYTES, SALT_LENGTH_IN_BYTES}, ioutils::{read_u32, write_u32}};
+use argon2::password_hash::rand_core::RngCore;
+use chacha20poly1305::{aead::{Aead, OsRng, Payload}, AeadCore, ChaCha20Poly1305, KeyInit};
+use crate::{constants::{ARCHIVE_ID_LENGTH_IN_BYTES, CHUNK_SIZE, SALT_LENGTH_IN_BYTES}, error::ArchiveError, ioutils::{read_u32, write_u32}};
 
+use zeroize::Zeroizing;
+
+use super::{kdf::KdfParams, secret::SecretKey};
+
+/// The longest a sealed chunk can be: a full chunk, its compression flag
+/// and the authentication tag.
+pub const MAX_SEALED_CHUNK: u32 = CHUNK_SIZE as u32 + 1 + 16;
 
 
 
@@ -14,40 +21,198 @@ use crate::{constants::{KEY_LENGTH_IN_BYTES, SALT_LENGTH_IN_BYTES}, ioutils::{re
 ///
 /// [ 12 bytes of nonce ] [ (4 bytes) u32 representing encrypted length ] [ encrypted bytes ]
 pub fn write_encrypted<W: Write>(writer: &mut W, key: &[u8], data: &[u8]) -> Result<()> {
-    let cipher = ChaCha20Poly1305::new_from_slice(key)
-        .map_err(|e| anyhow!("Failed to create a ChaCha20Poly1305 instance from a block. Error: {e}"))?;
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-    let encrypted = cipher.encrypt(&nonce, data)
-        .map_err(|e| anyhow!("Failed to encrypt with error: {e}"))?;
- 
-
-    writer.write_all(&nonce)?;
+    write_encrypted_with_aad(writer, key, data, &[])
+}
 
-    write_u32(writer, encrypted.len() as u32)?;
-    writer.write_all(&encrypted)?;
-    Ok(())
+/// Like [write_encrypted] but also authenticates (without storing) the
+/// associated data `aad`, which must be presented again to decrypt.
+pub fn write_encrypted_with_aad<W: Write>(writer: &mut W, key: &[u8], data: &[u8], aad: &[u8]) -> Result<()> {
+    Sealed::seal(key, data, aad)?.write(writer)
 }
 
 
 /// Reads encrypted data out to a decrypted vector as per the format specified
 /// in [write_encrypted].
 pub fn read_encrypted<R: Read>(reader: &mut R, key: &[u8]) -> Result<Vec<u8>> {
-    let cipher = ChaCha20Poly1305::new_from_slice(key)
-        .map_err(|e| anyhow!("Failed to create a ChaCha20Poly1305 instance from a block. Error: {e}"))?;
-    
-    let nonce = &mut [0u8; 12];
-    reader.read_exact(nonce)?;
-    
+    read_encrypted_with_aad(reader, key, &[])
+}
+
+/// Reads data written by [write_encrypted_with_aad].
+pub fn read_encrypted_with_aad<R: Read>(reader: &mut R, key: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    read_sealed_within(reader, u32::MAX)?.open(key, aad)
+}
+
+/// An encrypted block as laid out by [write_encrypted], read but not
+/// yet decrypted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sealed {
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>
+}
+
+impl Sealed {
+    /// Encrypts `data` under a fresh random nonce, authenticating `aad`.
+    pub fn seal(key: &[u8], data: &[u8], aad: &[u8]) -> Result<Self> {
+        let cipher = ChaCha20Poly1305::new_from_slice(key)
+            .map_err(|e| anyhow!("Failed to create a ChaCha20Poly1305 instance from a block. Error: {e}"))?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|e| anyhow!("Failed to encrypt with error: {e}"))?;
+
+        Ok(Self {
+            nonce: nonce.into(),
+            ciphertext
+        })
+    }
+    /// The number of bytes [Sealed::write] writes.
+    pub fn encoded_len(&self) -> u64 {
+        12 + 4 + self.ciphertext.len() as u64
+    }
+    /// Writes the block as laid out by [write_encrypted].
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.nonce)?;
+        write_u32(writer, self.ciphertext.len().try_into()?)?;
+        writer.write_all(&self.ciphertext)?;
+        Ok(())
+    }
+    /// Decrypts the block, authenticating it against `aad`.
+    pub fn open(&self, key: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new_from_slice(key)
+            .map_err(|e| anyhow!("Failed to create a ChaCha20Poly1305 instance from a block. Error: {e}"))?;
+
+        let decrypted = cipher.decrypt(self.nonce.as_ref().into(), Payload { msg: &self.ciphertext, aad })
+            .map_err(|e| anyhow!("Decryption failed: {e}"))?;
+
+        Ok(decrypted)
+    }
+    /// Like [Sealed::open] for a block holding a wrapped key, which never
+    /// leaves memory that is wiped.
+    pub fn open_key(&self, key: &[u8], aad: &[u8]) -> Result<SecretKey> {
+        SecretKey::from_slice(&Zeroizing::new(self.open(key, aad)?))
+    }
+}
+
+/// Reads a sealed chunk without decrypting it.
+///
+/// A length beyond [MAX_SEALED_CHUNK] is damage: it fails with an
+/// [InvalidData](ErrorKind::InvalidData) I/O error before anything is
+/// allocated for it.
+pub fn read_sealed<R: Read>(reader: &mut R) -> Result<Sealed> {
+    read_sealed_within(reader, MAX_SEALED_CHUNK)
+}
 
+/// Reads an encrypted block of at most `limit` bytes without decrypting
+/// it.
+fn read_sealed_within<R: Read>(reader: &mut R, limit: u32) -> Result<Sealed> {
+    let mut nonce = [0u8; 12];
+    reader.read_exact(&mut nonce)?;
     let encrypted_len = read_u32(reader)?;
-    let mut data = vec![0u8; encrypted_len as usize];
-    reader.read_exact(&mut data)?;
+    if encrypted_len > limit {
+        Err(std::io::Error::new(ErrorKind::InvalidData, format!("Encrypted block claims {encrypted_len} bytes, more than the limit of {limit}.")))?
+    }
+    let mut ciphertext = vec![0u8; encrypted_len as usize];
+    reader.read_exact(&mut ciphertext)?;
+
+    Ok(Sealed {
+        nonce,
+        ciphertext
+    })
+}
+
+/// Binds the chunks of an entry to their place in the archive.
+///
+/// Every chunk is encrypted with the archive id, the index of its entry,
+/// its own index and whether it is the final chunk as associated data,
+/// in the manner of the STREAM construction. Moving, splicing or dropping
+/// chunks therefore makes decryption fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkContext {
+    /// Chunks of archives predating the header, which carry no
+    /// associated data and no final chunk.
+    Legacy,
+    Bound {
+        archive_id: [u8; ARCHIVE_ID_LENGTH_IN_BYTES],
+        file_index: u32
+    }
+}
+
+impl ChunkContext {
+    /// The index of the entry the chunks belong to.
+    pub fn file_index(&self) -> u32 {
+        match self {
+            Self::Legacy => 0,
+            Self::Bound { file_index, .. } => *file_index
+        }
+    }
+    /// The associated data for a chunk, laid out as:
+    ///
+    /// [ 16 bytes of archive id ] [ u32 file index ] [ u64 chunk index ] [ u8 final flag ]
+    pub fn associated_data(&self, chunk_index: u64, last: bool) -> Vec<u8> {
+        match self {
+            Self::Legacy => Vec::new(),
+            Self::Bound { archive_id, file_index } => {
+                let mut aad = Vec::with_capacity(ARCHIVE_ID_LENGTH_IN_BYTES + 13);
+                aad.extend_from_slice(archive_id);
+                aad.extend_from_slice(&file_index.to_le_bytes());
+                aad.extend_from_slice(&chunk_index.to_le_bytes());
+                aad.push(last as u8);
+                aad
+            }
+        }
+    }
+    /// Encrypts a chunk to the writer.
+    pub fn write_chunk<W: Write>(&self, writer: &mut W, key: &[u8], chunk_index: u64, last: bool, data: &[u8]) -> Result<()> {
+        Sealed::seal(key, data, &self.associated_data(chunk_index, last))?.write(writer)
+    }
+    /// Decrypts a chunk, turning an authentication failure into the
+    /// matching [ArchiveError].
+    pub fn open_chunk(&self, key: &[u8], sealed: &Sealed, chunk_index: u64, last: bool) -> Result<Vec<u8>> {
+        if let Ok(decrypted) = sealed.open(key, &self.associated_data(chunk_index, last)) {
+            return Ok(decrypted);
+        }
+
+        let file_index = self.file_index();
+        if *self == Self::Legacy {
+            Err(ArchiveError::ChunkAuthentication { file_index, chunk_index })?
+        }
+
+        // The chunk is genuine but in the wrong place in the stream.
+        if sealed.open(key, &self.associated_data(chunk_index, !last)).is_ok() {
+            if last {
+                Err(ArchiveError::Truncated { file_index, chunk_index })?
+            } else {
+                Err(ArchiveError::TrailingChunks { file_index, chunk_index })?
+            }
+        }
+        Err(ArchiveError::ChunkAuthentication { file_index, chunk_index })?
+    }
+}
+
+/// Computes a keyed BLAKE3 MAC over `data` with a key derived from
+/// `key` for the given purpose, so the encryption key itself is never
+/// used for two different primitives.
+pub fn compute_mac(key: &[u8], purpose: &str, data: &[u8]) -> [u8; 32] {
+    let mac_key = blake3::derive_key(purpose, key);
+    *blake3::keyed_hash(&mac_key, data).as_bytes()
+}
 
+/// Checks a MAC produced by [compute_mac] in constant time.
+pub fn verify_mac(key: &[u8], purpose: &str, data: &[u8], mac: &[u8; 32]) -> bool {
+    let mac_key = blake3::derive_key(purpose, key);
+    blake3::keyed_hash(&mac_key, data) == blake3::Hash::from_bytes(*mac)
+}
 
-    let decrypted = cipher.decrypt(nonce.as_ref().into(), data.as_ref())
-        .map_err(|e| anyhow!("Decryption failed: {e}"))?;
+/// Generates a random archive key, which encrypts the chunks and the
+/// table and is itself stored wrapped in the key slots.
+pub fn generate_key() -> SecretKey {
+    SecretKey::from_fn(|key| OsRng.fill_bytes(key))
+}
 
-    Ok(decrypted)
+/// Generates a random archive id.
+pub fn generate_archive_id() -> [u8; ARCHIVE_ID_LENGTH_IN_BYTES] {
+    let mut id = [0u8; ARCHIVE_ID_LENGTH_IN_BYTES];
+    OsRng.fill_bytes(&mut id);
+    id
 }
 
 
@@ -65,12 +230,14 @@ pub fn generate_salt() -> [u8; SALT_LENGTH_IN_BYTES] {
     salt
 }
 
-/// Creates a key from a salt and the UTF-8 bytes of a passowrd.
-pub fn create_key(salt: &[u8], password: &[u8]) -> Result<Vec<u8>> {
-    let mut key = [0u8; KEY_LENGTH_IN_BYTES];
-    Argon2::default().hash_password_into(password, &salt, &mut key)
-        .map_err(|e| anyhow!("Failed to produce password with error: {e}"))?;
-    Ok(key.to_vec())
+/// Creates a key from a salt and the UTF-8 bytes of a password using
+/// the given Argon2 parameters.
+pub fn create_key(salt: &[u8], password: &[u8], params: &KdfParams) -> Result<SecretKey> {
+    let hasher = params.hasher()?;
+    let mut hashed = Ok(());
+    let key = SecretKey::from_fn(|key| hashed = hasher.hash_password_into(password, salt, key));
+    hashed.map_err(|e| anyhow!("Failed to produce password with error: {e}"))?;
+    Ok(key)
 }
 
 
@@ -85,7 +252,7 @@ mod tests {
 
     use crate::security::secure::read_encrypted;
 
-    use super::{create_key, generate_salt, write_encrypted};
+    use super::{create_key, generate_salt, write_encrypted, KdfParams};
 
     #[test]
     fn test_password_gen() -> Result<()> {
@@ -98,11 +265,11 @@ mod tests {
             let salt = generate_salt();
             let password = generate_salt();
 
-            let key = create_key(&salt, &password)?;
-            if set.contains(&key) {
+            let key = create_key(&salt, &password, &KdfParams::default())?;
+            if set.contains(key.as_bytes()) {
                 panic!("Encountered a duplicate entry. Is the password generator truly random?");
             }
-            set.insert(key);
+            set.insert(*key.as_bytes());
         }
 
         Ok(())
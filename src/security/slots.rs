@@ -0,0 +1,301 @@
+use std::{fmt, io::{Cursor, Read, Write}};
+
+use anyhow::{anyhow, Result};
+
+use crate::{constants::{ARCHIVE_ID_LENGTH_IN_BYTES, KEY_SLOT_LENGTH, SALT_LENGTH_IN_BYTES}, ioutils::{read_byte, read_full, read_u32}};
+
+use super::{credentials::{Credentials, Factors}, kdf::KdfParams, recipients::Recipient, secret::SecretKey, secure::{create_key, generate_salt, Sealed}};
+
+/// A slot holding the archive key wrapped with a credential, in the
+/// manner of LUKS.
+///
+/// The archive key is random and encrypts the chunks and the table, so
+/// slots can be added and removed without touching either. Each slot is
+/// serialized into exactly [KEY_SLOT_LENGTH] bytes:
+///
+/// [ u8 kind ] [ descriptor ] [ wrapped key ] [ zero padding ]
+///
+/// where a password slot's descriptor is the byte of its [Factors], its
+/// 17 bytes of kdf parameters and 32 bytes of salt, and a recipient slot's descriptor is the
+/// 32 byte ephemeral X25519 public key. The wrapped key is laid out as by
+/// [Sealed::write] and authenticated against the archive id followed by
+/// the kind and descriptor of the slot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeySlot {
+    #[default]
+    Empty,
+    /// The archive key wrapped with a key derived from a password, a
+    /// keyfile or both.
+    Password {
+        factors: Factors,
+        kdf_params: KdfParams,
+        salt: [u8; SALT_LENGTH_IN_BYTES],
+        wrapped_key: Sealed
+    },
+    /// The archive key wrapped to an X25519 [Recipient].
+    X25519 {
+        ephemeral_public: [u8; 32],
+        wrapped_key: Sealed
+    },
+    /// The implicit slot of legacy archives, whose archive key is derived
+    /// from the password directly. It is never written.
+    Legacy {
+        salt: [u8; SALT_LENGTH_IN_BYTES]
+    }
+}
+
+impl KeySlot {
+    /// Wraps `key` with a key derived from the password and keyfile of
+    /// `credentials`, whichever are present. Both are then needed to
+    /// unwrap it.
+    pub fn wrap_password(key: &[u8], credentials: &Credentials, kdf_params: &KdfParams, archive_id: &[u8; ARCHIVE_ID_LENGTH_IN_BYTES]) -> Result<Self> {
+        let factors = Factors::of(credentials)
+            .ok_or_else(|| anyhow!("A password slot needs a password, a keyfile or both."))?;
+        let material = factors.material(credentials).unwrap_or_default();
+        let salt = generate_salt();
+        let wrapping_key = create_key(&salt, &material, kdf_params)?;
+
+        let aad = [archive_id.as_slice(), &password_descriptor(factors, kdf_params, &salt)?].concat();
+        Ok(Self::Password {
+            factors,
+            kdf_params: *kdf_params,
+            salt,
+            wrapped_key: Sealed::seal(&wrapping_key, key, &aad)?
+        })
+    }
+    /// Wraps `key` to `recipient`, which can unwrap it with its identity.
+    pub fn wrap_recipient(key: &[u8], recipient: &Recipient, archive_id: &[u8; ARCHIVE_ID_LENGTH_IN_BYTES]) -> Result<Self> {
+        let (wrapping_key, ephemeral_public) = recipient.wrapping_key()?;
+        let aad = [archive_id.as_slice(), &recipient_descriptor(&ephemeral_public)].concat();
+        Ok(Self::X25519 {
+            ephemeral_public,
+            wrapped_key: Sealed::seal(&wrapping_key, key, &aad)?
+        })
+    }
+    /// Unwraps the archive key with whichever of `credentials` applies to
+    /// the slot, returning `None` if they do not open it.
+    pub fn unlock(&self, credentials: &Credentials, archive_id: &[u8; ARCHIVE_ID_LENGTH_IN_BYTES]) -> Result<Option<SecretKey>> {
+        match (self, &credentials.password) {
+            (Self::Password { factors, kdf_params, salt, wrapped_key }, _) => {
+                let Some(material) = factors.material(credentials) else {
+                    return Ok(None);
+                };
+                if kdf_params.m_cost > credentials.memory_limit {
+                    return Err(anyhow!("A key slot asks for {} KiB of memory to derive its key, more than the limit of {} KiB.", kdf_params.m_cost, credentials.memory_limit));
+                }
+                let wrapping_key = create_key(salt, &material, kdf_params)?;
+                let aad = self.associated_data(archive_id)?;
+                Ok(wrapped_key.open_key(&wrapping_key, &aad).ok())
+            },
+            (Self::X25519 { ephemeral_public, wrapped_key }, _) => {
+                let aad = self.associated_data(archive_id)?;
+                for identity in &credentials.identities {
+                    // Low order keys cannot come from an honest writer.
+                    let Ok(wrapping_key) = identity.wrapping_key(ephemeral_public) else {
+                        return Ok(None);
+                    };
+                    if let Ok(key) = wrapped_key.open_key(&wrapping_key, &aad) {
+                        return Ok(Some(key));
+                    }
+                }
+                Ok(None)
+            },
+            (Self::Legacy { salt }, Some(password)) => Ok(Some(create_key(salt, password.as_bytes(), &KdfParams::default())?)),
+            _ => Ok(None)
+        }
+    }
+    /// The secrets a password slot is unlocked with.
+    pub fn factors(&self) -> Option<Factors> {
+        match self {
+            Self::Password { factors, .. } => Some(*factors),
+            Self::Legacy { .. } => Some(Factors::Password),
+            _ => None
+        }
+    }
+    /// The Argon2 parameters of a password slot.
+    pub fn kdf_params(&self) -> Option<&KdfParams> {
+        match self {
+            Self::Password { kdf_params, .. } => Some(kdf_params),
+            _ => None
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        *self == Self::Empty
+    }
+    /// The kind and descriptor of the slot.
+    fn descriptor(&self) -> Result<Vec<u8>> {
+        match self {
+            Self::Empty => Ok(vec![0x00]),
+            Self::Password { factors, kdf_params, salt, .. } => password_descriptor(*factors, kdf_params, salt),
+            Self::X25519 { ephemeral_public, .. } => Ok(recipient_descriptor(ephemeral_public)),
+            Self::Legacy { .. } => Err(anyhow!("Legacy key slots cannot be written."))
+        }
+    }
+    fn associated_data(&self, archive_id: &[u8; ARCHIVE_ID_LENGTH_IN_BYTES]) -> Result<Vec<u8>> {
+        Ok([archive_id.as_slice(), &self.descriptor()?].concat())
+    }
+    /// Writes the slot, padded to [KEY_SLOT_LENGTH] bytes.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut bytes = self.descriptor()?;
+        if let Self::Password { wrapped_key, .. } | Self::X25519 { wrapped_key, .. } = self {
+            wrapped_key.write(&mut bytes)?;
+        }
+        if bytes.len() > KEY_SLOT_LENGTH {
+            return Err(anyhow!("A key slot of {} bytes does not fit in {KEY_SLOT_LENGTH} bytes.", bytes.len()));
+        }
+        bytes.resize(KEY_SLOT_LENGTH, 0);
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+    /// Reads a slot written by [KeySlot::write].
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut bytes = [0u8; KEY_SLOT_LENGTH];
+        if read_full(reader, &mut bytes)? != KEY_SLOT_LENGTH {
+            return Err(anyhow!("The archive header is truncated within its key slots."));
+        }
+
+        let mut reader = Cursor::new(&bytes[1..]);
+        match bytes[0] {
+            0x00 => Ok(Self::Empty),
+            0x01 => {
+                let factors = Factors::from_byte(read_byte(&mut reader)?)?;
+                let kdf_params = KdfParams::read(&mut reader)?;
+                let mut salt = [0u8; SALT_LENGTH_IN_BYTES];
+                reader.read_exact(&mut salt)?;
+                Ok(Self::Password {
+                    factors,
+                    kdf_params,
+                    salt,
+                    wrapped_key: read_wrapped_key(&mut reader)?
+                })
+            },
+            0x02 => {
+                let mut ephemeral_public = [0u8; 32];
+                reader.read_exact(&mut ephemeral_public)?;
+                Ok(Self::X25519 {
+                    ephemeral_public,
+                    wrapped_key: read_wrapped_key(&mut reader)?
+                })
+            },
+            other => Err(anyhow!("Unknown key slot kind 0x{other:02x} in the archive header."))
+        }
+    }
+}
+
+fn read_wrapped_key<R: Read>(reader: &mut R) -> Result<Sealed> {
+    let mut nonce = [0u8; 12];
+    reader.read_exact(&mut nonce)?;
+    let length = read_u32(reader)? as usize;
+    if length > KEY_SLOT_LENGTH {
+        return Err(anyhow!("A key slot holds a wrapped key longer than the slot."));
+    }
+    let mut ciphertext = vec![0u8; length];
+    reader.read_exact(&mut ciphertext)?;
+    Ok(Sealed { nonce, ciphertext })
+}
+
+fn recipient_descriptor(ephemeral_public: &[u8; 32]) -> Vec<u8> {
+    [[0x02].as_slice(), ephemeral_public].concat()
+}
+
+fn password_descriptor(factors: Factors, kdf_params: &KdfParams, salt: &[u8; SALT_LENGTH_IN_BYTES]) -> Result<Vec<u8>> {
+    let mut bytes = vec![0x01, factors as u8];
+    kdf_params.write(&mut bytes)?;
+    bytes.extend_from_slice(salt);
+    Ok(bytes)
+}
+
+impl fmt::Display for KeySlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty"),
+            Self::Password { factors, kdf_params, .. } => write!(f, "{factors}, {:?} {:?} (m={} KiB, t={}, p={})",
+                kdf_params.algorithm,
+                kdf_params.version,
+                kdf_params.m_cost,
+                kdf_params.t_cost,
+                kdf_params.p_cost
+            ),
+            Self::X25519 { .. } => write!(f, "x25519 recipient"),
+            Self::Legacy { .. } => write!(f, "password, legacy")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+
+    use crate::{constants::KEY_SLOT_LENGTH, security::{credentials::{Credentials, Keyfile}, recipients::Identity, secure::{generate_archive_id, generate_key}}, testing::test_params};
+
+    use super::KeySlot;
+
+    #[test]
+    pub fn test_password_slot() -> Result<()> {
+        let key = generate_key();
+        let archive_id = generate_archive_id();
+        let slot = KeySlot::wrap_password(&key, &Credentials::password("password"), &test_params(), &archive_id)?;
+
+        let mut export = Cursor::new(Vec::new());
+        slot.write(&mut export)?;
+        assert_eq!(export.get_ref().len(), KEY_SLOT_LENGTH);
+        export.set_position(0);
+        let slot = KeySlot::read(&mut export)?;
+
+        assert_eq!(slot.unlock(&Credentials::password("password"), &archive_id)?, Some(key));
+        assert_eq!(slot.unlock(&Credentials::password("wrong"), &archive_id)?, None);
+        // Slots cannot be moved to another archive.
+        assert_eq!(slot.unlock(&Credentials::password("password"), &generate_archive_id())?, None);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_keyfile_slot() -> Result<()> {
+        let key = generate_key();
+        let archive_id = generate_archive_id();
+        let keyfile = Keyfile::from_contents(b"random bytes");
+        let both = Credentials {
+            keyfile: Some(keyfile.clone()),
+            ..Credentials::password("weak")
+        };
+        let slot = KeySlot::wrap_password(&key, &both, &test_params(), &archive_id)?;
+
+        let mut export = Cursor::new(Vec::new());
+        slot.write(&mut export)?;
+        export.set_position(0);
+        let slot = KeySlot::read(&mut export)?;
+
+        assert_eq!(slot.unlock(&both, &archive_id)?, Some(key));
+        assert_eq!(slot.unlock(&Credentials::password("weak"), &archive_id)?, None);
+        let keyfile_only = Credentials { keyfile: Some(keyfile), ..Credentials::default() };
+        assert_eq!(slot.unlock(&keyfile_only, &archive_id)?, None);
+        let other_keyfile = Credentials {
+            keyfile: Some(Keyfile::from_contents(b"random bytes!")),
+            ..Credentials::password("weak")
+        };
+        assert_eq!(slot.unlock(&other_keyfile, &archive_id)?, None);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_recipient_slot() -> Result<()> {
+        let key = generate_key();
+        let archive_id = generate_archive_id();
+        let identity = Identity::generate();
+        let slot = KeySlot::wrap_recipient(&key, &identity.recipient(), &archive_id)?;
+
+        let mut export = Cursor::new(Vec::new());
+        slot.write(&mut export)?;
+        export.set_position(0);
+        let slot = KeySlot::read(&mut export)?;
+
+        let credentials = Credentials::identities(vec![Identity::generate(), identity]);
+        assert_eq!(slot.unlock(&credentials, &archive_id)?, Some(key));
+        assert_eq!(slot.unlock(&Credentials::identities(vec![Identity::generate()]), &archive_id)?, None);
+        assert_eq!(slot.unlock(&Credentials::password("password"), &archive_id)?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use std::{fmt, io::{Read, Seek, Write}, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use zeroize::Zeroizing;
+
+use crate::{constants::CHUNK_SIZE, ioutils::{read_byte, read_i32, write_i32}};
+
+/// The chunk was stored as is because compressing it did not help.
+const CHUNK_STORED: u8 = 0x00;
+/// The chunk was compressed with the algorithm of its entry.
+const CHUNK_COMPRESSED: u8 = 0x01;
+
+/// The algorithm the chunks of an entry are compressed with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CompressionAlgorithm {
+    #[default]
+    None = 0x00,
+    Zstd = 0x01,
+    Lz4 = 0x02,
+    Deflate = 0x03
+}
+
+impl CompressionAlgorithm {
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0x00 => Ok(Self::None),
+            0x01 => Ok(Self::Zstd),
+            0x02 => Ok(Self::Lz4),
+            0x03 => Ok(Self::Deflate),
+            _ => Err(anyhow!("Unknown compression algorithm 0x{byte:02x} in the file table."))
+        }
+    }
+    /// The level used when none is given.
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Self::None | Self::Lz4 => 0,
+            Self::Zstd => zstd::DEFAULT_COMPRESSION_LEVEL,
+            Self::Deflate => 6
+        }
+    }
+}
+
+impl FromStr for CompressionAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            "deflate" => Ok(Self::Deflate),
+            _ => Err(anyhow!("Unknown compression algorithm {name:?}, expected none, zstd, lz4 or deflate."))
+        }
+    }
+}
+
+impl fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+            Self::Deflate => "deflate"
+        })
+    }
+}
+
+/// How the chunks of an entry are compressed before encryption.
+///
+/// Every chunk of a compressed entry starts with a byte saying whether
+/// it was compressed or, when compression did not make it smaller,
+/// stored as is. Chunks of uncompressed entries carry no such byte.
+///
+/// Serialized as:
+///
+/// [ u8 algorithm ] [ i32 level ]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    /// The level the entry was compressed at, recorded for information
+    /// only as decompression does not need it.
+    pub level: i32
+}
+
+impl Compression {
+    /// Chunks are stored as is.
+    pub const NONE: Self = Self { algorithm: CompressionAlgorithm::None, level: 0 };
+
+    /// Creates and validates compression settings.
+    pub fn new(algorithm: CompressionAlgorithm, level: i32) -> Result<Self> {
+        let compression = Self { algorithm, level };
+        compression.validate()?;
+        Ok(compression)
+    }
+    /// Uses the default level of `algorithm`.
+    pub fn with_default_level(algorithm: CompressionAlgorithm) -> Self {
+        Self { algorithm, level: algorithm.default_level() }
+    }
+    pub fn validate(&self) -> Result<()> {
+        let valid = match self.algorithm {
+            CompressionAlgorithm::None | CompressionAlgorithm::Lz4 => self.level == 0,
+            CompressionAlgorithm::Zstd => zstd::compression_level_range().contains(&self.level),
+            CompressionAlgorithm::Deflate => (0..=9).contains(&self.level)
+        };
+        if !valid {
+            return Err(anyhow!("Compression level {} is not supported by {}.", self.level, self.algorithm));
+        }
+        Ok(())
+    }
+    /// Compresses a chunk of at most [CHUNK_SIZE] bytes, falling back to
+    /// storing it when that is smaller.
+    pub fn compress_chunk(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match self.algorithm {
+            CompressionAlgorithm::None => return Ok(data.to_vec()),
+            CompressionAlgorithm::Zstd => zstd::bulk::compress(data, self.level)?,
+            CompressionAlgorithm::Lz4 => lz4_flex::block::compress(data),
+            CompressionAlgorithm::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::new(self.level as u32));
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+        };
+
+        let (flag, contents) = if compressed.len() < data.len() {
+            (CHUNK_COMPRESSED, compressed.as_slice())
+        } else {
+            (CHUNK_STORED, data)
+        };
+        let mut chunk = Vec::with_capacity(contents.len() + 1);
+        chunk.push(flag);
+        chunk.extend_from_slice(contents);
+        Ok(chunk)
+    }
+    /// Reverses [Compression::compress_chunk], refusing chunks that would
+    /// decompress to more than [CHUNK_SIZE] bytes.
+    ///
+    /// Takes and returns plaintext, both are wiped when dropped.
+    pub fn decompress_chunk(&self, chunk: Zeroizing<Vec<u8>>) -> Result<Zeroizing<Vec<u8>>> {
+        if self.algorithm == CompressionAlgorithm::None {
+            return Ok(chunk);
+        }
+        let (flag, contents) = chunk.split_first()
+            .ok_or_else(|| anyhow!("A compressed chunk is missing its compression flag."))?;
+        match *flag {
+            CHUNK_STORED => return Ok(Zeroizing::new(contents.to_vec())),
+            CHUNK_COMPRESSED => {},
+            other => Err(anyhow!("Unknown chunk compression flag 0x{other:02x}."))?
+        }
+
+        let decompressed = Zeroizing::new(match self.algorithm {
+            CompressionAlgorithm::None => unreachable!(),
+            CompressionAlgorithm::Zstd => zstd::bulk::decompress(contents, CHUNK_SIZE)?,
+            CompressionAlgorithm::Lz4 => {
+                let mut decompressed = vec![0u8; CHUNK_SIZE];
+                let length = lz4_flex::block::decompress_into(contents, &mut decompressed)
+                    .map_err(|e| anyhow!("Failed to decompress an LZ4 chunk: {e}"))?;
+                decompressed.truncate(length);
+                decompressed
+            },
+            CompressionAlgorithm::Deflate => {
+                let mut decompressed = Vec::with_capacity(CHUNK_SIZE);
+                DeflateDecoder::new(contents).take(CHUNK_SIZE as u64 + 1).read_to_end(&mut decompressed)?;
+                decompressed
+            }
+        });
+        if decompressed.len() > CHUNK_SIZE {
+            return Err(anyhow!("A compressed chunk expands beyond the chunk size."));
+        }
+        Ok(decompressed)
+    }
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&[self.algorithm as u8])?;
+        write_i32(writer, self.level)?;
+        Ok(())
+    }
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let algorithm = CompressionAlgorithm::from_byte(read_byte(reader)?)?;
+        Ok(Self {
+            algorithm,
+            level: read_i32(reader)?
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+    use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+    use zeroize::Zeroizing;
+
+    use crate::constants::CHUNK_SIZE;
+
+    use super::{Compression, CompressionAlgorithm};
+
+    const ALGORITHMS: [CompressionAlgorithm; 4] = [
+        CompressionAlgorithm::None,
+        CompressionAlgorithm::Zstd,
+        CompressionAlgorithm::Lz4,
+        CompressionAlgorithm::Deflate
+    ];
+
+    #[test]
+    pub fn test_compression_roundtrip() -> Result<()> {
+        let text = b"the quick brown fox jumps over the lazy dog\n".repeat(CHUNK_SIZE / 44);
+        let mut random = vec![0u8; CHUNK_SIZE];
+        OsRng.fill_bytes(&mut random);
+
+        for algorithm in ALGORITHMS {
+            let compression = Compression::with_default_level(algorithm);
+
+            let compressed = compression.compress_chunk(&text)?;
+            if algorithm != CompressionAlgorithm::None {
+                assert!(compressed.len() < text.len() / 4);
+            }
+            assert_eq!(*compression.decompress_chunk(Zeroizing::new(compressed))?, text);
+
+            // Incompressible data is stored with only the flag added.
+            let stored = compression.compress_chunk(&random)?;
+            assert!(stored.len() <= random.len() + 1);
+            assert_eq!(*compression.decompress_chunk(Zeroizing::new(stored))?, random);
+
+            assert!(compression.decompress_chunk(Zeroizing::new(compression.compress_chunk(&[])?))?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_compression_record() -> Result<()> {
+        let compression = Compression::new(CompressionAlgorithm::Deflate, 9)?;
+        let mut export = Cursor::new(Vec::new());
+        compression.write(&mut export)?;
+        export.set_position(0);
+        assert_eq!(Compression::read(&mut export)?, compression);
+
+        assert!(Compression::new(CompressionAlgorithm::Deflate, 10).is_err());
+        assert!(Compression::new(CompressionAlgorithm::Lz4, 3).is_err());
+        assert!("brotli".parse::<CompressionAlgorithm>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, Result};
+
+use crate::{constants::{ARCHIVE_ID_LENGTH_IN_BYTES, FORMAT_VERSION, KEY_SLOT_COUNT, KEY_SLOT_LENGTH, LEGACY_FORMAT_VERSION, MAGIC, SALT_LENGTH_IN_BYTES}, error::ArchiveError, ioutils::{read_byte, read_u16, read_u32, write_u16, write_u32}, security::{credentials::Credentials, secret::SecretKey, secure::{generate_archive_id, ChunkContext}, slots::KeySlot}, structure::recovery::write_in_place};
+
+/// The flag bits understood by this build. Readers refuse archives
+/// that set any other bit.
+pub const KNOWN_FLAGS: u32 = 0;
+
+/// The length of the header in front of the key slots.
+const FIXED_HEADER_LENGTH: u64 = MAGIC.len() as u64 + 2 + 1 + 1 + 4 + ARCHIVE_ID_LENGTH_IN_BYTES as u64;
+
+/// The size of the header of a versioned archive in bytes.
+pub const HEADER_LENGTH: u64 = FIXED_HEADER_LENGTH + (KEY_SLOT_COUNT * KEY_SLOT_LENGTH) as u64;
+
+/// The cipher used to encrypt the chunks and the table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CipherId {
+    ChaCha20Poly1305 = 0x01
+}
+
+impl CipherId {
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0x01 => Ok(Self::ChaCha20Poly1305),
+            _ => Err(anyhow!("Unknown cipher identifier 0x{byte:02x} in archive header."))
+        }
+    }
+}
+
+/// The function deriving keys from passwords.
+///
+/// The variant, version and costs are recorded in every password slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KdfId {
+    Argon2 = 0x01
+}
+
+impl KdfId {
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0x01 => Ok(Self::Argon2),
+            _ => Err(anyhow!("Unknown key derivation function identifier 0x{byte:02x} in archive header."))
+        }
+    }
+}
+
+/// The fixed header at the start of every archive.
+///
+/// Laid out as:
+///
+/// [ 6 bytes of magic ] [ u16 version ] [ u8 cipher ] [ u8 kdf ] [ u32 flags ] [ 16 bytes of archive id ] [ 8 key slots of 128 bytes ]
+///
+/// Everything in front of the key slots is authenticated by the
+/// trailer. The slots are not, so they can be rewritten in place; each
+/// authenticates itself instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveHeader {
+    pub version: u16,
+    pub cipher: CipherId,
+    pub kdf: KdfId,
+    pub flags: u32,
+    /// Random id binding the chunks and key slots to this archive.
+    pub archive_id: [u8; ARCHIVE_ID_LENGTH_IN_BYTES],
+    pub key_slots: [KeySlot; KEY_SLOT_COUNT]
+}
+
+impl ArchiveHeader {
+    /// Creates a header for a new archive in the current format, with
+    /// every key slot empty.
+    pub fn new() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            cipher: CipherId::ChaCha20Poly1305,
+            kdf: KdfId::Argon2,
+            flags: 0,
+            archive_id: generate_archive_id(),
+            key_slots: Default::default()
+        }
+    }
+    /// Whether this header describes an archive written before the
+    /// header existed.
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_FORMAT_VERSION
+    }
+    /// The context binding the chunks of the entry at `file_index`.
+    pub fn chunk_context(&self, file_index: u32) -> ChunkContext {
+        if self.is_legacy() {
+            ChunkContext::Legacy
+        } else {
+            ChunkContext::Bound {
+                archive_id: self.archive_id,
+                file_index
+            }
+        }
+    }
+    /// The position of the `index`-th key slot within the archive.
+    pub fn slot_offset(index: usize) -> u64 {
+        FIXED_HEADER_LENGTH + (index * KEY_SLOT_LENGTH) as u64
+    }
+    /// Unwraps the archive key with the first key slot `credentials`
+    /// open.
+    ///
+    /// Fails with [ArchiveError::Authentication] if they open none.
+    pub fn unlock(&self, credentials: &Credentials) -> Result<SecretKey> {
+        Ok(self.unlock_slot(credentials)?.1)
+    }
+    /// Like [ArchiveHeader::unlock] but also returns the index of the
+    /// slot that was opened.
+    ///
+    /// Stops at the first slot whose key derivation needs more memory
+    /// than `credentials` allow, rather than trying the others.
+    pub fn unlock_slot(&self, credentials: &Credentials) -> Result<(usize, SecretKey)> {
+        for (index, slot) in self.key_slots.iter().enumerate() {
+            if let Some(key) = slot.unlock(credentials, &self.archive_id)? {
+                return Ok((index, key));
+            }
+        }
+        Err(ArchiveError::Authentication)?
+    }
+    /// The bytes of the header the trailer authenticates, everything but
+    /// the key slots.
+    pub fn authenticated_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        write_u16(&mut bytes, self.version)?;
+        bytes.push(self.cipher as u8);
+        bytes.push(self.kdf as u8);
+        write_u32(&mut bytes, self.flags)?;
+        bytes.extend_from_slice(&self.archive_id);
+        Ok(bytes)
+    }
+    /// Writes the header to a [Writer](std::io).
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.authenticated_bytes()?)?;
+        for slot in &self.key_slots {
+            slot.write(writer)?;
+        }
+        Ok(())
+    }
+    /// Overwrites the `index`-th key slot of the archive in place, along
+    /// with the parity of its recovery record if it has one.
+    pub fn write_slot<F: Read + Write + Seek>(&self, file: &mut F, index: usize) -> Result<()> {
+        let slot = self.key_slots.get(index)
+            .ok_or_else(|| anyhow!("The archive has no key slot {index}."))?;
+        let mut bytes = Vec::with_capacity(KEY_SLOT_LENGTH);
+        slot.write(&mut bytes)?;
+        write_in_place(file, Self::slot_offset(index), &bytes)
+    }
+    /// Reads and validates the header from the start of the reader.
+    ///
+    /// The version, cipher, key derivation function and flags are all
+    /// checked here, before any key is derived.
+    ///
+    /// Archives that do not begin with the magic bytes are treated as
+    /// legacy archives whose first bytes are the salt the key was
+    /// derived with, the caller is responsible for rejecting them if the
+    /// rest of the file does not match that layout.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+
+        let magic = &mut [0u8; MAGIC.len()];
+        if reader.read_exact(magic).is_err() {
+            return Err(anyhow!("Not a sonors archive: the file is too short to contain a header."));
+        }
+
+        if *magic != MAGIC {
+            reader.seek(SeekFrom::Start(0))?;
+            let mut salt = [0u8; SALT_LENGTH_IN_BYTES];
+            reader.read_exact(&mut salt)
+                .map_err(|_| anyhow!("Not a sonors archive: missing magic bytes."))?;
+
+            let mut key_slots: [KeySlot; KEY_SLOT_COUNT] = Default::default();
+            key_slots[0] = KeySlot::Legacy { salt };
+            return Ok(Self {
+                version: LEGACY_FORMAT_VERSION,
+                cipher: CipherId::ChaCha20Poly1305,
+                kdf: KdfId::Argon2,
+                flags: 0,
+                archive_id: [0u8; ARCHIVE_ID_LENGTH_IN_BYTES],
+                key_slots
+            });
+        }
+
+        let version = read_u16(reader)?;
+        if version != FORMAT_VERSION {
+            return Err(anyhow!("Unsupported archive format version {version}, this build reads version {FORMAT_VERSION} and legacy archives."));
+        }
+        let cipher = CipherId::from_byte(read_byte(reader)?)?;
+        let kdf = KdfId::from_byte(read_byte(reader)?)?;
+        let flags = read_u32(reader)?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(anyhow!("Archive header sets unknown flags 0x{:08x}.", flags & !KNOWN_FLAGS));
+        }
+
+        let mut archive_id = [0u8; ARCHIVE_ID_LENGTH_IN_BYTES];
+        reader.read_exact(&mut archive_id)?;
+
+        let mut key_slots: [KeySlot; KEY_SLOT_COUNT] = Default::default();
+        for slot in key_slots.iter_mut() {
+            *slot = KeySlot::read(reader)?;
+        }
+
+        Ok(Self {
+            version,
+            cipher,
+            kdf,
+            flags,
+            archive_id,
+            key_slots
+        })
+    }
+}
+
+impl Default for ArchiveHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    use anyhow::Result;
+
+    use crate::{error::ArchiveError, security::{credentials::Credentials, kdf::KdfParams, secure::generate_key, slots::KeySlot}, testing::test_params};
+
+    use super::{ArchiveHeader, HEADER_LENGTH};
+
+    #[test]
+    pub fn test_header_roundtrip() -> Result<()> {
+        let key = generate_key();
+        let mut header = ArchiveHeader::new();
+        header.key_slots[2] = KeySlot::wrap_password(&key, &Credentials::password("password"), &test_params(), &header.archive_id)?;
+
+        let mut export = Cursor::new(Vec::new());
+        header.write(&mut export)?;
+        assert_eq!(export.get_ref().len() as u64, HEADER_LENGTH);
+
+        let header = ArchiveHeader::read(&mut export)?;
+        assert_eq!(header.unlock(&Credentials::password("password"))?, key);
+        let error = header.unlock(&Credentials::password("wrong")).unwrap_err();
+        assert_eq!(error.downcast_ref::<ArchiveError>(), Some(&ArchiveError::Authentication));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_unlock_stops_at_memory_limit() -> Result<()> {
+        let key = generate_key();
+        let mut header = ArchiveHeader::new();
+        header.key_slots[0] = KeySlot::wrap_password(&key, &Credentials::password("other"), &KdfParams::new(2048, 1, 1)?, &header.archive_id)?;
+        header.key_slots[1] = KeySlot::wrap_password(&key, &Credentials::password("password"), &test_params(), &header.archive_id)?;
+
+        assert_eq!(header.unlock_slot(&Credentials::password("password"))?.0, 1);
+        let limited = Credentials {
+            memory_limit: 1024,
+            ..Credentials::password("password")
+        };
+        let error = header.unlock(&limited).unwrap_err();
+        assert!(error.to_string().contains("limit of 1024 KiB"));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_header_rejects_future_version() -> Result<()> {
+        let mut export = Cursor::new(Vec::new());
+        ArchiveHeader::new().write(&mut export)?;
+
+        export.seek(SeekFrom::Start(6))?;
+        export.write_all(&u16::MAX.to_le_bytes())?;
+
+        assert!(ArchiveHeader::read(&mut export).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_header_rejects_unknown_kdf() -> Result<()> {
+        let mut export = Cursor::new(Vec::new());
+        ArchiveHeader::new().write(&mut export)?;
+
+        export.seek(SeekFrom::Start(9))?;
+        export.write_all(&[0xff])?;
+
+        let error = ArchiveHeader::read(&mut export).unwrap_err();
+        assert!(error.to_string().contains("key derivation function"));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_header_rejects_huge_time_cost() -> Result<()> {
+        let mut header = ArchiveHeader::new();
+        let params = test_params();
+        header.key_slots[0] = KeySlot::wrap_password(&generate_key(), &Credentials::password("password"), &params, &header.archive_id)?;
+        let mut export = Cursor::new(Vec::new());
+        header.write(&mut export)?;
+
+        // Raise the pass count of the slot, which no MAC covers yet.
+        let mut serialized = Vec::new();
+        params.write(&mut serialized)?;
+        let mut bytes = export.into_inner();
+        let at = bytes.windows(serialized.len()).position(|window| window == serialized).expect("serialized parameters") + 9;
+        bytes[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let error = ArchiveHeader::read(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(error.to_string().contains("time cost"));
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_header_rejects_short_file() {
+        let mut export = Cursor::new(b"SON".to_vec());
+        assert!(ArchiveHeader::read(&mut export).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::{fs::Metadata, io::{Read, Seek, Write}, path::Path};
+
+use anyhow::Result;
+use filetime::FileTime;
+
+use crate::ioutils::{read_i64, read_string, read_u32, write_i64, write_string, write_u32};
+
+/// Whether extraction restores the owner and group of entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Ownership {
+    /// Restore ownership only when running as root, like tar.
+    #[default]
+    Auto,
+    /// Always restore ownership, failing if it cannot be changed.
+    Restore,
+    /// Leave extracted entries owned by the extracting user.
+    Skip
+}
+
+impl Ownership {
+    fn should_restore(&self) -> bool {
+        match self {
+            Self::Auto => is_root(),
+            Self::Restore => true,
+            Self::Skip => false
+        }
+    }
+}
+
+/// A point in time as seconds and nanoseconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanoseconds: u32
+}
+
+impl From<FileTime> for Timestamp {
+    fn from(time: FileTime) -> Self {
+        Self {
+            seconds: time.unix_seconds(),
+            nanoseconds: time.nanoseconds()
+        }
+    }
+}
+
+impl From<Timestamp> for FileTime {
+    fn from(time: Timestamp) -> Self {
+        FileTime::from_unix_time(time.seconds, time.nanoseconds)
+    }
+}
+
+/// The Unix metadata recorded for every entry.
+///
+/// Serialized as:
+///
+/// [ u32 mode ] [ i64 + u32 mtime ] [ i64 + u32 atime ] [ u32 uid ] [ u32 gid ] [ user name ] [ group name ]
+///
+/// where the names are length prefixed and empty when unknown.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeMetadata {
+    /// The permission bits, including setuid, setgid and sticky.
+    pub mode: u32,
+    pub mtime: Timestamp,
+    pub atime: Timestamp,
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>
+}
+
+impl NodeMetadata {
+    /// Captures the metadata of a file on disk.
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            use nix::unistd::{Gid, Group, Uid, User};
+
+            Self {
+                mode: metadata.mode() & 0o7777,
+                mtime: FileTime::from_last_modification_time(metadata).into(),
+                atime: FileTime::from_last_access_time(metadata).into(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                user: User::from_uid(Uid::from_raw(metadata.uid())).ok().flatten().map(|user| user.name),
+                group: Group::from_gid(Gid::from_raw(metadata.gid())).ok().flatten().map(|group| group.name)
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            Self {
+                mode: if metadata.permissions().readonly() { 0o444 } else { 0o644 } | if metadata.is_dir() { 0o111 } else { 0 },
+                mtime: FileTime::from_last_modification_time(metadata).into(),
+                atime: FileTime::from_last_access_time(metadata).into(),
+                ..Self::default()
+            }
+        }
+    }
+    /// Applies the metadata to an extracted entry at `path`.
+    ///
+    /// Ownership is changed first, as doing so may clear the setuid and
+    /// setgid bits, which are dropped when it is not restored. The times
+    /// are set last, as changing anything else could update them.
+    ///
+    /// Symbolic links themselves are changed rather than their targets
+    /// and keep their permissions, which are meaningless.
+    pub fn restore(&self, path: &Path, ownership: Ownership) -> Result<()> {
+        let is_symlink = path.symlink_metadata()?.file_type().is_symlink();
+        #[cfg(unix)]
+        {
+            use std::{fs::{set_permissions, Permissions}, os::unix::fs::{lchown, PermissionsExt}};
+
+            // Without the recorded owner the setuid and setgid bits would
+            // grant the privileges of whoever extracts, as in tar.
+            let mut mode = self.mode;
+            if ownership.should_restore() {
+                let (uid, gid) = self.resolve_owner();
+                lchown(path, Some(uid), Some(gid))?;
+            } else {
+                mode &= !0o6000;
+            }
+            if !is_symlink {
+                set_permissions(path, Permissions::from_mode(mode))?;
+            }
+        }
+        #[cfg(not(unix))]
+        if !is_symlink {
+            let _ = ownership;
+            let mut permissions = path.metadata()?.permissions();
+            permissions.set_readonly(self.mode & 0o222 == 0);
+            std::fs::set_permissions(path, permissions)?;
+        }
+        if is_symlink {
+            filetime::set_symlink_file_times(path, self.atime.into(), self.mtime.into())?;
+        } else {
+            filetime::set_file_times(path, self.atime.into(), self.mtime.into())?;
+        }
+        Ok(())
+    }
+    /// Maps the recorded owner onto this system, preferring the names
+    /// and falling back to the numeric ids when they are unknown here.
+    #[cfg(unix)]
+    fn resolve_owner(&self) -> (u32, u32) {
+        use nix::unistd::{Group, User};
+
+        let uid = self.user.as_deref()
+            .and_then(|name| User::from_name(name).ok().flatten())
+            .map_or(self.uid, |user| user.uid.as_raw());
+        let gid = self.group.as_deref()
+            .and_then(|name| Group::from_name(name).ok().flatten())
+            .map_or(self.gid, |group| group.gid.as_raw());
+        (uid, gid)
+    }
+    /// Writes the metadata to a [Writer](std::io).
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
+        write_u32(writer, self.mode)?;
+        for time in [self.mtime, self.atime] {
+            write_i64(writer, time.seconds)?;
+            write_u32(writer, time.nanoseconds)?;
+        }
+        write_u32(writer, self.uid)?;
+        write_u32(writer, self.gid)?;
+        write_string(writer, self.user.as_deref().unwrap_or_default())?;
+        write_string(writer, self.group.as_deref().unwrap_or_default())?;
+        Ok(())
+    }
+    /// Reads metadata written by [NodeMetadata::write].
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let mode = read_u32(reader)?;
+        let mut times = [Timestamp::default(); 2];
+        for time in times.iter_mut() {
+            time.seconds = read_i64(reader)?;
+            time.nanoseconds = read_u32(reader)?;
+        }
+        Ok(Self {
+            mode,
+            mtime: times[0],
+            atime: times[1],
+            uid: read_u32(reader)?,
+            gid: read_u32(reader)?,
+            user: Some(read_string(reader)?).filter(|name| !name.is_empty()),
+            group: Some(read_string(reader)?).filter(|name| !name.is_empty())
+        })
+    }
+}
+
+/// Whether the process runs with the privileges to change ownership.
+fn is_root() -> bool {
+    #[cfg(unix)]
+    {
+        nix::unistd::geteuid().is_root()
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{metadata, write}, io::Cursor};
+
+    use anyhow::Result;
+
+    use super::{NodeMetadata, Ownership, Timestamp};
+
+    #[test]
+    pub fn test_metadata_roundtrip() -> Result<()> {
+        let recorded = NodeMetadata {
+            mode: 0o4750,
+            mtime: Timestamp { seconds: 1_700_000_000, nanoseconds: 42 },
+            atime: Timestamp { seconds: -5, nanoseconds: 0 },
+            uid: 1000,
+            gid: 100,
+            user: Some("me".into()),
+            group: None
+        };
+
+        let mut export = Cursor::new(Vec::new());
+        recorded.write(&mut export)?;
+        export.set_position(0);
+        assert_eq!(NodeMetadata::read(&mut export)?, recorded);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_restore() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("file");
+        write(&path, b"contents")?;
+
+        let recorded = NodeMetadata {
+            mode: 0o640,
+            mtime: Timestamp { seconds: 1_000_000_000, nanoseconds: 0 },
+            atime: Timestamp { seconds: 1_000_000_001, nanoseconds: 0 },
+            ..NodeMetadata::default()
+        };
+        recorded.restore(&path, Ownership::Skip)?;
+
+        let restored = NodeMetadata::from_metadata(&metadata(&path)?);
+        assert_eq!(restored.mtime, recorded.mtime);
+        #[cfg(unix)]
+        assert_eq!(restored.mode, 0o640);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn test_restore_drops_setuid_without_owner() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join("file");
+        write(&path, b"contents")?;
+
+        let recorded = NodeMetadata { mode: 0o6755, ..NodeMetadata::default() };
+        recorded.restore(&path, Ownership::Skip)?;
+        assert_eq!(NodeMetadata::from_metadata(&metadata(&path)?).mode, 0o755);
+        Ok(())
+    }
+}
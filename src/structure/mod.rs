@@ -1,3 +1,7 @@
 pub mod table;
 pub mod node;
-pub mod file;
+pub mod header;
+pub mod trailer;
+pub mod metadata;
+pub mod compression;
+pub mod recovery;
@@ -1,39 +1,336 @@
-use std::{fs::File, io::{BufReader, Read, Seek, Write}, path::PathBuf};
+use std::{fs::File, io::{BufReader, Read, Seek, Write}, path::{Path, PathBuf}};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use crate::{constants::CHUNK_SIZE, security::secure::write_encrypted};
+use crate::{constants::CHUNK_SIZE, ioutils::{read_bool, read_byte, read_full, read_pathbuf, read_raw_pathbuf, read_u32, read_u64, write_bool, write_pathbuf, write_u32, write_u64, PositionWriter}, security::secure::ChunkContext};
 
-#[derive(Clone, Debug)]
+use super::{compression::Compression, metadata::NodeMetadata};
+
+/// What kind of file system object a node represents.
+///
+/// Serialized as a tag byte followed by the link target, if any.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A regular file, the only kind with contents.
+    #[default]
+    File,
+    Directory,
+    /// A symbolic link, whose target is stored verbatim and may point
+    /// anywhere.
+    Symlink { target: PathBuf },
+    /// A hard link to the entry stored under `target`, usually stored
+    /// before it but after it once the target has been replaced.
+    Hardlink { target: PathBuf }
+}
+
+impl NodeKind {
+    fn write<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Self::File => writer.write_all(&[0x00])?,
+            Self::Directory => writer.write_all(&[0x01])?,
+            Self::Symlink { target } => {
+                writer.write_all(&[0x02])?;
+                write_pathbuf(writer, target)?;
+            },
+            Self::Hardlink { target } => {
+                writer.write_all(&[0x03])?;
+                write_pathbuf(writer, target)?;
+            }
+        }
+        Ok(())
+    }
+    fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        Ok(match read_byte(reader)? {
+            0x00 => Self::File,
+            0x01 => Self::Directory,
+            0x02 => Self::Symlink { target: read_raw_pathbuf(reader)? },
+            0x03 => Self::Hardlink { target: read_pathbuf(reader)? },
+            other => Err(anyhow!("Unknown node kind 0x{other:02x} in the file table."))?
+        })
+    }
+}
+
+/// Where a chunk of a file lies in the archive and how much of the file
+/// it holds, so any part of the file can be reached without reading the
+/// chunks before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkLocation {
+    /// The position of the encrypted chunk, past its status byte.
+    pub offset: u64,
+    /// The number of bytes of the file in the chunk once decrypted and
+    /// decompressed.
+    pub length: u32
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct ArchivalNode {
+    /// The path the node is stored under within the archive.
     pub path: PathBuf,
-    pub is_leaf: bool,
+    pub kind: NodeKind,
+    /// How the chunks of a file are compressed.
+    pub compression: Compression,
+    /// The Unix metadata of the node, absent for legacy archives.
+    pub metadata: Option<NodeMetadata>,
+    /// The chunks of a file in order, filled in by [ArchivalNode::write].
+    /// Empty for legacy archives.
+    pub chunks: Vec<ChunkLocation>,
+    /// The BLAKE3 hash of the contents of a file, filled in by
+    /// [ArchivalNode::write]. Absent for legacy archives.
+    pub hash: Option<[u8; 32]>,
+    /// Where the contents are read from when writing, if not `path`.
+    /// This is never stored in the archive.
+    pub source: Option<PathBuf>
 }
 
 impl ArchivalNode {
-    pub fn write<W: Write + Seek>(&self, writer: &mut W, key: &[u8]) -> Result<u64> {
+    /// The size of the contents of a file in bytes, as recorded by its
+    /// chunk index.
+    pub fn size(&self) -> u64 {
+        self.chunks.iter().map(|chunk| u64::from(chunk.length)).sum()
+    }
+    /// Whether the node is a regular file and so has contents.
+    pub fn is_file(&self) -> bool {
+        self.kind == NodeKind::File
+    }
+    /// Where the contents of the node are read from when writing.
+    pub fn source(&self) -> &Path {
+        self.source.as_deref().unwrap_or(&self.path)
+    }
+    /// Writes the record describing the node to the file table.
+    pub fn write_record<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
+        self.kind.write(writer)?;
+        write_pathbuf(writer, &self.path)?;
+        self.compression.write(writer)?;
+        if self.is_file() {
+            write_u32(writer, self.chunks.len().try_into()?)?;
+            for chunk in &self.chunks {
+                write_u64(writer, chunk.offset)?;
+                write_u32(writer, chunk.length)?;
+            }
+            write_bool(writer, self.hash.is_some())?;
+            if let Some(hash) = &self.hash {
+                writer.write_all(hash)?;
+            }
+        }
+
+        write_bool(writer, self.metadata.is_some())?;
+        if let Some(metadata) = &self.metadata {
+            metadata.write(writer)?;
+        }
+        Ok(())
+    }
+    /// Reads a record written by [ArchivalNode::write_record].
+    pub fn read_record<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let kind = NodeKind::read(reader)?;
+        let path = read_pathbuf(reader)?;
+        let compression = Compression::read(reader)?;
+        let mut chunks = Vec::new();
+        let mut hash = None;
+        if kind == NodeKind::File {
+            for _ in 0..read_u32(reader)? {
+                let offset = read_u64(reader)?;
+                let length = read_u32(reader)?;
+                if length as usize > CHUNK_SIZE {
+                    return Err(anyhow!("A chunk of {path:?} is recorded as {length} bytes, more than the chunk size."));
+                }
+                chunks.push(ChunkLocation { offset, length });
+            }
+            if read_bool(reader)? {
+                let mut bytes = [0u8; 32];
+                reader.read_exact(&mut bytes)?;
+                hash = Some(bytes);
+            }
+        }
+        let metadata = if read_bool(reader)? {
+            Some(NodeMetadata::read(reader)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            path,
+            kind,
+            compression,
+            metadata,
+            chunks,
+            hash,
+            source: None
+        })
+    }
+    /// Writes the contents of the node as a sequence of compressed and
+    /// encrypted chunks bound to the node by `context`, returning the
+    /// starting position and recording where each chunk went in
+    /// [ArchivalNode::chunks] and the hash of the contents in
+    /// [ArchivalNode::hash].
+    ///
+    /// Each chunk is preceded by `0x00` and the sequence is terminated by
+    /// `0x01`. A file always has at least one chunk, the last of which is
+    /// flagged as final so that truncation can be detected, other kinds
+    /// of node have no contents and write nothing.
+    pub fn write<W: Write + Seek>(&mut self, writer: &mut W, key: &[u8], context: &ChunkContext) -> Result<u64> {
         let starting_position = writer.stream_position()?;
+        self.chunks.clear();
+        self.hash = None;
+
+        if self.is_file() {
+            let mut reader = BufReader::new(File::open(self.source())?);
+
+            let mut current = vec![0u8; CHUNK_SIZE];
+            let mut next = vec![0u8; CHUNK_SIZE];
+            let mut current_len = read_full(&mut reader, &mut current)?;
+            let mut chunk_index = 0;
+            let mut hasher = blake3::Hasher::new();
+            let mut writer = PositionWriter::new(&mut *writer, starting_position);
 
-  
-        if self.is_leaf {
-            let mut reader = BufReader::new(File::open(&self.path)?);
-    
             loop {
-                let buf = &mut [0u8; CHUNK_SIZE];
-                let bytes_read = reader.read(buf)?;
-                if bytes_read == 0 {
+                // Read ahead so the final chunk can be flagged as such.
+                let next_len = if current_len == CHUNK_SIZE {
+                    read_full(&mut reader, &mut next)?
+                } else {
+                    0
+                };
+                let last = next_len == 0;
+
+                writer.write_all(&[0x00])?;
+                hasher.update(&current[..current_len]);
+                let chunk = self.compression.compress_chunk(&current[..current_len])?;
+                self.chunks.push(ChunkLocation {
+                    offset: writer.position(),
+                    length: current_len.try_into()?
+                });
+                context.write_chunk(&mut writer, key, chunk_index, last, &chunk)?;
+                if last {
                     break;
                 }
 
-                writer.write_all(&[0x00])?;
-                write_encrypted(writer, key, &buf[..bytes_read])?;
+                std::mem::swap(&mut current, &mut next);
+                current_len = next_len;
+                chunk_index += 1;
             }
             writer.write_all(&[0x01])?;
+            self.hash = Some(*hasher.finalize().as_bytes());
         }
         Ok(starting_position)
     }
 }
 
 
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use anyhow::Result;
+    use chacha20poly1305::{aead::OsRng, ChaCha20Poly1305, KeyInit};
+    use tempfile::NamedTempFile;
+
+    use crate::{constants::CHUNK_SIZE, error::ArchiveError, ioutils::transfer_archival_node, security::secure::{generate_archive_id, ChunkContext}, structure::{compression::{Compression, CompressionAlgorithm}, metadata::NodeMetadata}};
+
+    use super::{ArchivalNode, ChunkLocation};
+
+    /// Splits an encoded node into its chunk records, dropping the terminator.
+    fn split_chunks(encoded: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut position = 0;
+        while encoded[position] == 0x00 {
+            let length = u32::from_le_bytes(encoded[position + 13..position + 17].try_into().unwrap()) as usize;
+            chunks.push(encoded[position..position + 17 + length].to_vec());
+            position += 17 + length;
+        }
+        chunks
+    }
+
+    fn extract(encoded: Vec<u8>, key: &[u8], context: &ChunkContext) -> Result<Vec<u8>> {
+        let mut output = Cursor::new(Vec::new());
+        transfer_archival_node(&mut Cursor::new(encoded), &mut output, key, context, &Compression::NONE)?;
+        Ok(output.into_inner())
+    }
+
+    #[test]
+    pub fn test_chunks_are_bound() -> Result<()> {
+        let contents = (0..CHUNK_SIZE * 5 / 2).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&contents)?;
+
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let context = ChunkContext::Bound { archive_id: generate_archive_id(), file_index: 3 };
+        let mut node = ArchivalNode { path: file.path().to_path_buf(), ..Default::default() };
+
+        let mut encoded = Cursor::new(Vec::new());
+        node.write(&mut encoded, &key, &context)?;
+        let encoded = encoded.into_inner();
+        assert_eq!(extract(encoded.clone(), &key, &context)?, contents);
+
+        let chunks = split_chunks(&encoded);
+        assert_eq!(chunks.len(), 3);
+
+        // The chunk index points at each chunk past its status byte.
+        assert_eq!(node.chunks.len(), 3);
+        assert_eq!(node.chunks[1].offset, chunks[0].len() as u64 + 1);
+        assert_eq!(node.chunks[2].length as usize, CHUNK_SIZE / 2);
+        assert_eq!(node.size(), contents.len() as u64);
+        assert_eq!(node.hash, Some(*blake3::hash(&contents).as_bytes()));
 
+        // Reordered chunks.
+        let reordered = [chunks[1].clone(), chunks[0].clone(), chunks[2].clone(), vec![0x01]].concat();
+        let error = extract(reordered, &key, &context).unwrap_err();
+        assert_eq!(error.downcast_ref::<ArchiveError>(), Some(&ArchiveError::ChunkAuthentication { file_index: 3, chunk_index: 0 }));
 
+        // Dropped final chunk.
+        let truncated = [chunks[0].clone(), chunks[1].clone(), vec![0x01]].concat();
+        let error = extract(truncated, &key, &context).unwrap_err();
+        assert_eq!(error.downcast_ref::<ArchiveError>(), Some(&ArchiveError::Truncated { file_index: 3, chunk_index: 1 }));
+
+        // Spliced into another entry.
+        let other = ChunkContext::Bound { archive_id: generate_archive_id(), file_index: 3 };
+        let error = extract(encoded, &key, &other).unwrap_err();
+        assert_eq!(error.downcast_ref::<ArchiveError>(), Some(&ArchiveError::ChunkAuthentication { file_index: 3, chunk_index: 0 }));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_record_roundtrip() -> Result<()> {
+        let node = ArchivalNode {
+            path: "dir/file".into(),
+            compression: Compression::with_default_level(CompressionAlgorithm::Lz4),
+            metadata: Some(NodeMetadata { mode: 0o644, ..Default::default() }),
+            chunks: vec![ChunkLocation { offset: 1100, length: CHUNK_SIZE as u32 }, ChunkLocation { offset: 9000, length: 12 }],
+            hash: Some([7u8; 32]),
+            ..Default::default()
+        };
+        let mut record = Cursor::new(Vec::new());
+        node.write_record(&mut record)?;
+        record.set_position(0);
+
+        let read = ArchivalNode::read_record(&mut record)?;
+        assert_eq!((&read.path, &read.kind, read.compression, &read.metadata), (&node.path, &node.kind, node.compression, &node.metadata));
+        assert_eq!((&read.chunks, read.hash), (&node.chunks, node.hash));
+
+        // A chunk longer than any chunk is refused rather than allocated.
+        let oversized = ArchivalNode { chunks: vec![ChunkLocation { offset: 0, length: u32::MAX }], ..node };
+        let mut record = Cursor::new(Vec::new());
+        oversized.write_record(&mut record)?;
+        record.set_position(0);
+        assert!(ArchivalNode::read_record(&mut record).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_empty_file() -> Result<()> {
+        let file = NamedTempFile::new()?;
+
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let context = ChunkContext::Bound { archive_id: generate_archive_id(), file_index: 0 };
+        let mut node = ArchivalNode { path: file.path().to_path_buf(), ..Default::default() };
+
+        let mut encoded = Cursor::new(Vec::new());
+        node.write(&mut encoded, &key, &context)?;
+        let encoded = encoded.into_inner();
+        assert!(extract(encoded.clone(), &key, &context)?.is_empty());
+
+        // Removing the only chunk is detected.
+        let error = extract(vec![0x01], &key, &context).unwrap_err();
+        assert_eq!(error.downcast_ref::<ArchiveError>(), Some(&ArchiveError::Truncated { file_index: 0, chunk_index: 0 }));
+
+        Ok(())
+    }
+}
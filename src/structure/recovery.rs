@@ -0,0 +1,540 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::{ioutils::{read_u16, read_u32, read_u64, rfind, write_u16, write_u32, write_u64, Truncate}, structure::{header::ArchiveHeader, trailer::TRAILER_MAGIC}};
+
+/// The magic bytes closing an archive that carries a recovery record.
+pub const RECOVERY_MAGIC: [u8; 8] = *b"SNRSRCVR";
+
+/// The size of the footer locating the recovery record in bytes.
+const FOOTER_LENGTH: u64 = 8 + 4 + RECOVERY_MAGIC.len() as u64;
+
+/// The size of the fields of the descriptor before the hashes in bytes.
+const DESCRIPTOR_FIELDS_LENGTH: u64 = 8 + 4 + 2 + 2 + 1;
+
+/// The smallest data shard, so small archives do not carry a hash for
+/// every few bytes.
+const MIN_SHARD_SIZE: u64 = 4096;
+
+/// How many bytes of every shard are encoded at once, and covered by a
+/// hash of their own.
+const STRIPE_LENGTH: u64 = 65_536;
+
+/// The most shards Reed-Solomon over GF(2^8) supports.
+const MAX_SHARDS: usize = 256;
+
+/// Reed-Solomon parity stored after the trailer of an archive, from which
+/// damaged parts of the archive can be rebuilt without any key.
+///
+/// Laid out as:
+///
+/// [ descriptor ] [ parity ] [ descriptor ] [ u64 record offset ] [ u32 descriptor length ] [ 8 bytes of magic ]
+///
+/// where the descriptor, kept twice so one damaged copy does not lose
+/// the record, is:
+///
+/// [ u64 protected length ] [ u32 shard size ] [ u16 data shards ] [ u16 parity shards ] [ u8 percent ] [ 32 byte hash of every block ] [ 32 byte hash of the above ]
+///
+/// Everything in front of the record is protected, cut into data shards
+/// of `shard size` bytes, the last padded with zeros. Every shard is
+/// encoded in stripes of [STRIPE_LENGTH] bytes, and the part of a shard
+/// within a stripe, a block, is the unit damage is found and rebuilt in:
+/// each stripe can lose as many blocks as there are parity shards. The
+/// hashes are listed stripe by stripe, the data blocks before the parity
+/// blocks, and the parity is stored the same way so it is written and
+/// read in a single pass.
+///
+/// The key slots lie within the first stripe, so changing them only
+/// recomputes that stripe of the parity, see [write_in_place].
+///
+/// The record itself is not authenticated, repaired bytes are
+/// authenticated as usual when the archive is read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecoveryRecord {
+    pub protected_length: u64,
+    pub shard_size: u32,
+    pub data_shards: u16,
+    pub parity_shards: u16,
+    /// The share of the protected bytes the parity was sized as.
+    pub percent: u8,
+    /// The hash of every block, stripe by stripe.
+    pub hashes: Vec<[u8; 32]>
+}
+
+/// The blocks [repair] found damaged and rebuilt.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Repair {
+    pub data_blocks: usize,
+    pub parity_blocks: usize
+}
+
+impl Repair {
+    /// Whether nothing was damaged.
+    pub fn is_empty(&self) -> bool {
+        self.data_blocks + self.parity_blocks == 0
+    }
+}
+
+impl RecoveryRecord {
+    /// Sizes the shards so the parity takes about `percent` of
+    /// `protected_length` bytes, with at least one parity shard.
+    fn layout(protected_length: u64, percent: u8) -> Result<Self> {
+        if !(1..=100).contains(&percent) {
+            return Err(anyhow!("The recovery record must be between 1 and 100 percent of the archive, not {percent}."));
+        }
+        let parity_for = |data: u64| (data * u64::from(percent)).div_ceil(100);
+        let most_data = (1..MAX_SHARDS as u64).rev()
+            .find(|data| data + parity_for(*data) <= MAX_SHARDS as u64)
+            .unwrap_or(1);
+        let data_shards = most_data.min(protected_length.div_ceil(MIN_SHARD_SIZE)).max(1);
+        let shard_size = protected_length.div_ceil(data_shards);
+
+        Ok(Self {
+            protected_length,
+            shard_size: shard_size.try_into().map_err(|_| anyhow!("The archive is too large for a recovery record."))?,
+            data_shards: data_shards as u16,
+            parity_shards: parity_for(data_shards) as u16,
+            percent,
+            hashes: Vec::new()
+        })
+    }
+    /// The descriptor written in front of and behind the parity.
+    fn descriptor(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        write_u64(&mut bytes, self.protected_length)?;
+        write_u32(&mut bytes, self.shard_size)?;
+        write_u16(&mut bytes, self.data_shards)?;
+        write_u16(&mut bytes, self.parity_shards)?;
+        bytes.push(self.percent);
+        for hash in &self.hashes {
+            bytes.extend_from_slice(hash);
+        }
+        let hash = blake3::hash(&bytes);
+        bytes.extend_from_slice(hash.as_bytes());
+        Ok(bytes)
+    }
+    /// The length of the descriptor in bytes once every hash is known.
+    fn descriptor_length(&self) -> u64 {
+        DESCRIPTOR_FIELDS_LENGTH + 32 * (self.total_shards() * self.stripes().count()) as u64 + 32
+    }
+    /// Parses a descriptor, `None` if it is damaged.
+    fn parse_descriptor(bytes: &[u8]) -> Option<Self> {
+        let (fields, hash) = bytes.split_at_checked(bytes.len().checked_sub(32)?)?;
+        if blake3::hash(fields) != blake3::Hash::from_bytes(hash.try_into().ok()?) {
+            return None;
+        }
+        let mut reader = fields;
+        let mut record = Self {
+            protected_length: read_u64(&mut reader).ok()?,
+            shard_size: read_u32(&mut reader).ok()?,
+            data_shards: read_u16(&mut reader).ok()?,
+            parity_shards: read_u16(&mut reader).ok()?,
+            percent: *reader.first()?,
+            hashes: Vec::new()
+        };
+        if record.data_shards == 0 || record.parity_shards == 0 || record.descriptor_length() != bytes.len() as u64 {
+            return None;
+        }
+        record.hashes = reader[1..].chunks_exact(32).map(|hash| hash.try_into().expect("chunks of 32 bytes")).collect();
+        Some(record)
+    }
+    fn total_shards(&self) -> usize {
+        usize::from(self.data_shards) + usize::from(self.parity_shards)
+    }
+    /// The length of the parity in bytes.
+    fn parity_length(&self) -> u64 {
+        u64::from(self.parity_shards) * u64::from(self.shard_size)
+    }
+    /// The position and length of every stripe within a shard.
+    fn stripes(&self) -> impl Iterator<Item = (u64, usize)> {
+        let shard_size = u64::from(self.shard_size);
+        (0..shard_size).step_by(STRIPE_LENGTH as usize)
+            .map(move |start| (start, STRIPE_LENGTH.min(shard_size - start) as usize))
+    }
+    /// The hashes of the blocks of the stripe starting at `start`.
+    fn stripe_hashes(&mut self, start: u64) -> &mut [[u8; 32]] {
+        let first = (start / STRIPE_LENGTH) as usize * self.total_shards();
+        let total = self.total_shards();
+        &mut self.hashes[first..first + total]
+    }
+    /// Reads the recovery record closing the reader and the position it
+    /// starts at, `None` if the reader does not end with one.
+    ///
+    /// Fails if both copies of the descriptor are damaged.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Option<(u64, Self)>> {
+        let end = archive_end(reader)?;
+        Self::read_at(reader, end)
+    }
+    /// Like [RecoveryRecord::read] for a record ending at `end`.
+    fn read_at<R: Read + Seek>(reader: &mut R, end: u64) -> Result<Option<(u64, Self)>> {
+        let Some((offset, descriptor_length)) = read_footer(reader, end)? else {
+            return Ok(None);
+        };
+        let copies = [offset, end - FOOTER_LENGTH - descriptor_length];
+        for copy in copies {
+            let mut descriptor = vec![0u8; descriptor_length as usize];
+            reader.seek(SeekFrom::Start(copy))?;
+            reader.read_exact(&mut descriptor)?;
+            if let Some(record) = Self::parse_descriptor(&descriptor) {
+                let parity_fits = offset + 2 * descriptor_length + record.parity_length() + FOOTER_LENGTH == end;
+                if record.protected_length == offset && parity_fits {
+                    return Ok(Some((offset, record)));
+                }
+            }
+        }
+        Err(anyhow!("Both copies of the recovery record descriptor are damaged."))
+    }
+}
+
+/// Reads the footer of a recovery record ending at `end`, `None` if there
+/// is none.
+fn read_footer<R: Read + Seek>(reader: &mut R, end: u64) -> Result<Option<(u64, u64)>> {
+    if end < FOOTER_LENGTH {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(end - FOOTER_LENGTH))?;
+    let offset = read_u64(reader)?;
+    let descriptor_length = u64::from(read_u32(reader)?);
+    let mut magic = [0u8; RECOVERY_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+
+    let fits = offset.checked_add(2 * descriptor_length + FOOTER_LENGTH).is_some_and(|length| length <= end);
+    Ok((magic == RECOVERY_MAGIC && fits).then_some((offset, descriptor_length)))
+}
+
+/// Where the archive in the reader ends, its recovery record included.
+///
+/// That is the end of the reader, unless a write was interrupted and left
+/// bytes after the archive that neither a trailer nor a recovery record
+/// closes. The reader is then searched backwards for the last complete
+/// one, so the archive reads as it was before the write.
+pub fn archive_end<R: Read + Seek>(reader: &mut R) -> Result<u64> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    if closing_magic(reader, length)?.is_some() {
+        return Ok(length);
+    }
+    let mut before = length;
+    while let Some(end) = rfind(reader, &[&TRAILER_MAGIC, &RECOVERY_MAGIC], before)? {
+        // Only the key can tell a trailer apart, a record checks itself.
+        let complete = match closing_magic(reader, end)? {
+            Some(TRAILER_MAGIC) => true,
+            _ => RecoveryRecord::read_at(reader, end).is_ok_and(|record| record.is_some())
+        };
+        if complete {
+            return Ok(end);
+        }
+        before = end - 1;
+    }
+    Ok(length)
+}
+
+/// The magic of a trailer or recovery record ending at `end`, if one does.
+fn closing_magic<R: Read + Seek>(reader: &mut R, end: u64) -> Result<Option<[u8; 8]>> {
+    if end < RECOVERY_MAGIC.len() as u64 {
+        return Ok(None);
+    }
+    let mut magic = [0u8; RECOVERY_MAGIC.len()];
+    reader.seek(SeekFrom::Start(end - magic.len() as u64))?;
+    reader.read_exact(&mut magic)?;
+    Ok([TRAILER_MAGIC, RECOVERY_MAGIC].contains(&magic).then_some(magic))
+}
+
+/// The length of the archive in front of its recovery record, or the
+/// whole archive if it has none.
+pub fn data_end<R: Read + Seek>(reader: &mut R) -> Result<u64> {
+    let end = archive_end(reader)?;
+    match read_footer(reader, end)? {
+        Some((offset, _)) => Ok(offset),
+        None => Ok(end)
+    }
+}
+
+/// Writes a recovery record sized as `percent` of the archive in `file`,
+/// replacing any it already has.
+///
+/// Legacy archives are refused, their readers expect the table at the
+/// very end.
+pub fn protect<F: Read + Write + Seek + Truncate>(file: &mut F, percent: u8) -> Result<RecoveryRecord> {
+    if ArchiveHeader::read(file)?.is_legacy() {
+        return Err(anyhow!("Legacy archives cannot carry a recovery record, extract and recreate the archive instead."));
+    }
+    let protected_length = data_end(file)?;
+    let mut record = RecoveryRecord::layout(protected_length, percent)?;
+    file.truncate(protected_length)?;
+
+    let written = write_record(file, &mut record);
+    if written.is_err() {
+        // Without its footer the partial record would hide the trailer.
+        let _ = file.truncate(protected_length);
+    }
+    written?;
+    Ok(record)
+}
+
+/// Encodes the parity and writes the record after the protected bytes.
+fn write_record<F: Read + Write + Seek>(file: &mut F, record: &mut RecoveryRecord) -> Result<()> {
+    let codec = codec(record)?;
+    let parity_start = record.protected_length + record.descriptor_length();
+
+    for (start, length) in record.stripes() {
+        let mut blocks = vec![vec![0u8; length]; record.total_shards()];
+        for (index, block) in blocks[..usize::from(record.data_shards)].iter_mut().enumerate() {
+            read_protected(file, record, index as u64 * u64::from(record.shard_size) + start, block)?;
+        }
+        codec.encode(&mut blocks).map_err(|e| anyhow!("Failed to encode the recovery record: {e:?}"))?;
+
+        record.hashes.extend(blocks.iter().map(|block| *blake3::hash(block).as_bytes()));
+        write_parity(file, record, parity_start, start, &blocks)?;
+    }
+    write_descriptors(file, record)?;
+    write_u64(file, record.protected_length)?;
+    write_u32(file, record.descriptor_length().try_into()?)?;
+    file.write_all(&RECOVERY_MAGIC)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Rebuilds the damaged blocks of the archive in `file` from its recovery
+/// record, in place and without any key.
+///
+/// Fails if the archive has no recovery record or a stripe has more
+/// damaged blocks than there are parity shards, once every stripe that
+/// can be rebuilt has been.
+pub fn repair<F: Read + Write + Seek>(file: &mut F) -> Result<Repair> {
+    let (_, record) = RecoveryRecord::read(file)?
+        .ok_or_else(|| anyhow!("The archive has no recovery record, or the end of the archive locating it is damaged."))?;
+    let codec = codec(&record)?;
+    let data_shards = usize::from(record.data_shards);
+    let parity_start = record.protected_length + record.descriptor_length();
+
+    let mut repair = Repair::default();
+    let mut lost = Vec::new();
+    for ((start, length), hashes) in record.stripes().zip(record.hashes.chunks_exact(record.total_shards())) {
+        // The damaged blocks are those that no longer match their hash.
+        let blocks = read_stripe(file, &record, parity_start, start, length)?;
+        let damaged = blocks.iter().zip(hashes)
+            .map(|(block, hash)| blake3::hash(block) != blake3::Hash::from_bytes(*hash))
+            .collect::<Vec<_>>();
+        let count = damaged.iter().filter(|damaged| **damaged).count();
+        if count == 0 {
+            continue;
+        }
+        if count > usize::from(record.parity_shards) {
+            lost.push(start);
+            continue;
+        }
+
+        let mut blocks = blocks.into_iter().zip(&damaged).map(|(block, damaged)| (block, !damaged)).collect::<Vec<_>>();
+        codec.reconstruct(&mut blocks).map_err(|e| anyhow!("Failed to rebuild the damaged blocks: {e:?}"))?;
+        for (index, (block, _)) in blocks.iter().enumerate().filter(|(index, _)| damaged[*index]) {
+            if index < data_shards {
+                write_protected(file, &record, index as u64 * u64::from(record.shard_size) + start, block)?;
+                repair.data_blocks += 1;
+            } else {
+                let position = parity_start + start * u64::from(record.parity_shards) + ((index - data_shards) * length) as u64;
+                file.seek(SeekFrom::Start(position))?;
+                file.write_all(block)?;
+                repair.parity_blocks += 1;
+            }
+        }
+    }
+    file.flush()?;
+    if let Some(first) = lost.first() {
+        return Err(anyhow!("{} stripes, the first at offset {first} of every shard, have more damaged blocks than the {} the recovery record can rebuild.", lost.len(), record.parity_shards));
+    }
+    Ok(repair)
+}
+
+/// Writes `bytes` at `offset` within the first stripe of the archive in
+/// `file`, updating its recovery record first if it has one.
+///
+/// Only the first stripe of the parity is recomputed, so the time taken
+/// does not depend on the size of the archive. Fails without writing
+/// anything if the first stripe is damaged, so damage is never folded
+/// into the parity. Should the update be interrupted the parity already
+/// describes `bytes`, and [repair] completes it rather than restoring
+/// what was there before.
+pub fn write_in_place<F: Read + Write + Seek>(file: &mut F, offset: u64, bytes: &[u8]) -> Result<()> {
+    if let Some((_, mut record)) = RecoveryRecord::read(file)? {
+        let (start, length) = record.stripes().next().expect("at least one stripe");
+        let end = offset + bytes.len() as u64;
+        if end > length as u64 {
+            return Err(anyhow!("Only the first {length} bytes of the archive can be rewritten in place."));
+        }
+        let parity_start = record.protected_length + record.descriptor_length();
+        let mut blocks = read_stripe(file, &record, parity_start, start, length)?;
+        let intact = blocks[..usize::from(record.data_shards)].iter().zip(record.stripe_hashes(start))
+            .all(|(block, hash)| blake3::hash(block) == blake3::Hash::from_bytes(*hash));
+        if !intact {
+            return Err(anyhow!("The start of the archive is damaged, repair it first."));
+        }
+
+        blocks[0][offset as usize..end as usize].copy_from_slice(bytes);
+        codec(&record)?.encode(&mut blocks).map_err(|e| anyhow!("Failed to encode the recovery record: {e:?}"))?;
+        for (hash, block) in record.stripe_hashes(start).iter_mut().zip(&blocks) {
+            *hash = *blake3::hash(block).as_bytes();
+        }
+        write_parity(file, &record, parity_start, start, &blocks)?;
+        write_descriptors(file, &record)?;
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+fn codec(record: &RecoveryRecord) -> Result<ReedSolomon> {
+    ReedSolomon::new(record.data_shards.into(), record.parity_shards.into())
+        .map_err(|e| anyhow!("Unsupported recovery record layout: {e:?}"))
+}
+
+/// Writes both copies of the descriptor.
+fn write_descriptors<W: Write + Seek>(writer: &mut W, record: &RecoveryRecord) -> Result<()> {
+    let descriptor = record.descriptor()?;
+    writer.seek(SeekFrom::Start(record.protected_length))?;
+    writer.write_all(&descriptor)?;
+    writer.seek(SeekFrom::Start(record.protected_length + record.descriptor_length() + record.parity_length()))?;
+    writer.write_all(&descriptor)?;
+    Ok(())
+}
+
+/// Writes the parity blocks of the stripe starting at `start`.
+fn write_parity<W: Write + Seek>(writer: &mut W, record: &RecoveryRecord, parity_start: u64, start: u64, blocks: &[Vec<u8>]) -> Result<()> {
+    writer.seek(SeekFrom::Start(parity_start + start * u64::from(record.parity_shards)))?;
+    for block in &blocks[usize::from(record.data_shards)..] {
+        writer.write_all(block)?;
+    }
+    Ok(())
+}
+
+/// Reads the data and parity blocks of the stripe starting at `start`.
+fn read_stripe<R: Read + Seek>(reader: &mut R, record: &RecoveryRecord, parity_start: u64, start: u64, length: usize) -> Result<Vec<Vec<u8>>> {
+    let mut blocks = vec![vec![0u8; length]; record.total_shards()];
+    let (data, parity) = blocks.split_at_mut(record.data_shards.into());
+    for (index, block) in data.iter_mut().enumerate() {
+        read_protected(reader, record, index as u64 * u64::from(record.shard_size) + start, block)?;
+    }
+    reader.seek(SeekFrom::Start(parity_start + start * u64::from(record.parity_shards)))?;
+    for block in parity {
+        reader.read_exact(block)?;
+    }
+    Ok(blocks)
+}
+
+/// Reads the protected bytes at `offset`, with zeros past the protected
+/// length.
+fn read_protected<R: Read + Seek>(reader: &mut R, record: &RecoveryRecord, offset: u64, buf: &mut [u8]) -> Result<()> {
+    buf.fill(0);
+    let available = record.protected_length.saturating_sub(offset).min(buf.len() as u64) as usize;
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf[..available])?;
+    Ok(())
+}
+
+/// Writes rebuilt protected bytes at `offset`, leaving out the padding
+/// past the protected length.
+fn write_protected<W: Write + Seek>(writer: &mut W, record: &RecoveryRecord, offset: u64, buf: &[u8]) -> Result<()> {
+    let available = record.protected_length.saturating_sub(offset).min(buf.len() as u64) as usize;
+    writer.seek(SeekFrom::Start(offset))?;
+    writer.write_all(&buf[..available])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+
+    use crate::{archive::{ArchiveReader, ArchiveWriter}, security::credentials::Credentials, structure::{header::ArchiveHeader, trailer::TRAILER_LENGTH}, testing::{test_archive, TEST_PASSWORD}};
+
+    use super::{protect, repair, write_in_place, RecoveryRecord, Repair};
+
+    fn archive(contents: &[u8]) -> Result<Cursor<Vec<u8>>> {
+        Ok(Cursor::new(test_archive(&[("file", contents)])?))
+    }
+
+    fn extract(archive: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = ArchiveReader::new(Cursor::new(archive.to_vec()), TEST_PASSWORD)?;
+        let mut contents = Vec::new();
+        reader.extract_file("file", &mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    pub fn test_protect_and_repair() -> Result<()> {
+        let mut contents = vec![0u8; 200_000];
+        blake3::Hasher::new().finalize_xof().fill(&mut contents);
+        let mut archive = archive(&contents)?;
+        let original = archive.get_ref().clone();
+
+        let record = protect(&mut archive, 10)?;
+        assert_eq!(record.protected_length, original.len() as u64);
+        assert_eq!((record.data_shards, record.parity_shards), (50, 5));
+        assert_eq!(extract(archive.get_ref())?, contents);
+        assert!(repair(&mut archive)?.is_empty());
+
+        // Damage a key slot, a chunk, the table and the first copy of the descriptor.
+        let mut damaged = archive.clone().into_inner();
+        let table = original.len() - TRAILER_LENGTH as usize - 10;
+        damaged[ArchiveHeader::slot_offset(0) as usize + 40] ^= 0x01;
+        damaged[100_000] ^= 0x01;
+        damaged[table] ^= 0x01;
+        damaged[original.len()] ^= 0x01;
+        assert!(extract(&damaged).is_err());
+
+        let mut damaged = Cursor::new(damaged);
+        assert_eq!(RecoveryRecord::read(&mut damaged)?.map(|(_, read)| read), Some(record.clone()));
+        assert_eq!(repair(&mut damaged)?, Repair { data_blocks: 3, parity_blocks: 0 });
+        assert_eq!(damaged.get_ref()[..original.len()], original[..]);
+        assert_eq!(extract(damaged.get_ref())?, contents);
+
+        // One damaged block more than there is parity for.
+        let mut damaged = archive.into_inner();
+        for shard in 0..6 {
+            damaged[shard * record.shard_size as usize + 2000] ^= 0x01;
+        }
+        assert!(repair(&mut Cursor::new(damaged)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_write_in_place() -> Result<()> {
+        let mut archive = archive(b"contents")?;
+        protect(&mut archive, 50)?;
+        let slot = ArchiveHeader::slot_offset(3);
+
+        write_in_place(&mut archive, slot, &[0xaa; 16])?;
+        assert!(repair(&mut archive)?.is_empty());
+
+        // The parity holds the new bytes, so they are what is rebuilt.
+        archive.get_mut()[slot as usize] = 0x00;
+        assert_eq!(repair(&mut archive)?.data_blocks, 1);
+        assert_eq!(archive.get_ref()[slot as usize..slot as usize + 16], [0xaa; 16]);
+
+        // Damage is never folded into the parity.
+        archive.get_mut()[200] ^= 0x01;
+        assert!(write_in_place(&mut archive, slot, &[0xbb; 16]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_append_keeps_record() -> Result<()> {
+        let mut archive = archive(b"contents")?;
+        let record = protect(&mut archive, 100)?;
+        assert_eq!((record.data_shards, record.parity_shards), (1, 1));
+
+        let mut appended = ArchiveWriter::append(archive, &Credentials::password(TEST_PASSWORD))?.finish()?;
+        let (offset, rebuilt) = RecoveryRecord::read(&mut appended)?.expect("a recovery record");
+        assert_eq!((rebuilt.percent, rebuilt.protected_length), (100, offset));
+        assert!(offset > record.protected_length);
+
+        // The new table is protected as well.
+        appended.get_mut()[offset as usize - 100] ^= 0x01;
+        assert!(extract(appended.get_ref()).is_err());
+        assert_eq!(repair(&mut appended)?.data_blocks, 1);
+        assert_eq!(extract(appended.get_ref())?, b"contents");
+        Ok(())
+    }
+}
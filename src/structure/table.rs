@@ -1,30 +1,42 @@
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use crate::{constants::SALT_LENGTH_IN_BYTES, ioutils::{read_bool, read_pathbuf, read_u32, read_u64, write_bool, write_pathbuf}, security::secure::{create_key, read_encrypted, write_encrypted}};
-use anyhow::Result;
-use super::node::ArchivalNode;
+use std::{collections::HashSet, io::{Cursor, Read, Seek, SeekFrom, Write}};
+use crate::{error::ArchiveError, ioutils::{read_bool, read_pathbuf, read_u32, read_u64}, security::{credentials::Credentials, secret::SecretKey, secure::{read_encrypted, write_encrypted}}};
+use anyhow::{anyhow, Context, Result};
+use zeroize::Zeroizing;
+use super::{header::ArchiveHeader, node::{ArchivalNode, NodeKind}, trailer::Trailer};
 
 
+/// The file position recorded for a removed entry, which is followed by
+/// no node.
+const REMOVED_ENTRY: u64 = u64::MAX;
+
 /// Allows the indexing of the contents of the files and serves as the access
 /// mechanism for all archived volumes.
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct FileTable {
     /// The actual table. 
     ///
     /// (Index, File Positon, ArchivalNode)
     pub map: Vec<(u32, u64, ArchivalNode)>,
+    /// The indexes of removed entries.
+    ///
+    /// They stay recorded so they are never handed to another entry,
+    /// whose chunks could otherwise be swapped for those of the removed
+    /// one still lying in the archive.
+    pub removed: Vec<u32>,
     /// The encryption key being used for the table.
-    key: Vec<u8>,
-    /// The salt used for the table.
-    salt: Vec<u8>
+    key: SecretKey,
+    /// The header of the archive the table belongs to.
+    header: ArchiveHeader
 }
 
 impl FileTable {
     /// Creates a blank new file table.
-    pub fn new(key: Vec<u8>, salt: &[u8]) -> Self {
+    pub fn new(key: SecretKey, header: ArchiveHeader) -> Self {
         Self {
             map: Vec::default(),
+            removed: Vec::default(),
             key,
-            salt: salt.to_vec()
+            header
         }
     }
     /// Adds a node to the file table structure.
@@ -33,110 +45,153 @@ impl FileTable {
     /// - Index (position within the file table)
     /// - File Index (position within the file)
     /// - Node, an [ArchivalNode] representing the object to represent
-    /// within the table.
+    ///   within the table.
     pub fn add(&mut self, index: u32, file_index: u64, node: ArchivalNode) {
         self.map.push((index, file_index, node))
     }
-    /// Returns the key.
-    ///
-    /// # Safety
-    /// This function is potentially unsafe as cloning the key in 
-    /// any form could prevent it from being zeroed and therefore
-    /// leading to it being seen in memory.
-    pub unsafe fn key(&self) -> &[u8] {
+    /// Removes the entries with the given indexes, keeping their indexes
+    /// in [FileTable::removed].
+    pub fn remove(&mut self, indexes: &HashSet<u32>) {
+        self.map.retain(|(index, _, _)| !indexes.contains(index));
+        self.removed.extend(indexes);
+        self.removed.sort_unstable();
+        self.removed.dedup();
+    }
+    /// The index for the next entry added, above every index used so far.
+    pub fn next_index(&self) -> Result<u32> {
+        let highest = self.map.iter().map(|(index, _, _)| *index).chain(self.removed.iter().copied()).max();
+        match highest {
+            Some(highest) => highest.checked_add(1).ok_or_else(|| anyhow!("The archive has run out of entry indexes.")),
+            None => Ok(0)
+        }
+    }
+    /// Returns the key, which is wiped when the table is dropped.
+    pub fn key(&self) -> &SecretKey {
         &self.key
     }
+    /// Returns the header of the archive the table belongs to.
+    pub fn header(&self) -> &ArchiveHeader {
+        &self.header
+    }
     /// Creates a `FileTable` from a mutable reader object.
-    pub fn from_reader<T: Read + Seek>(reader: &mut T, password: &str) -> Result<Self> {
-        read_file_table(reader, password)
+    ///
+    /// The archive header is validated before any key is derived and the
+    /// trailer is authenticated before the table location is trusted.
+    pub fn from_reader<T: Read + Seek>(reader: &mut T, credentials: &Credentials) -> Result<Self> {
+        read_file_table(reader, credentials)
     }
     /// Writes the file table to a [Writer](std::io) object.
     pub fn write<T: Write + Seek>(&self, writer: &mut T) -> Result<()> {
         write_file_table(writer, self)
     }
-//    pub fn expand_into_files<T: Read + Seek>(&self, reader: &mut T, dest: impl AsRef<Path>) -> Result<()> {
- //       
-   // }
 }
 
 fn write_file_table<T: Write + Seek>(writer: &mut T, table: &FileTable) -> Result<()> {
     let current_position = writer.stream_position()?;
 
-
-
-    writer.write_all(&table.salt)?;
-
-
     // Create a write to to write pre-encryption.
-    let mut table_writer = Cursor::new(Vec::new());
+    let mut plaintext = Zeroizing::new(Vec::new());
+    let mut table_writer = Cursor::new(&mut *plaintext);
 
     for (key, value, node) in table.map.iter() {
         table_writer.write_all(&key.to_le_bytes())?;
         table_writer.write_all(&value.to_le_bytes())?;
-
-        write_bool(&mut table_writer, node.is_leaf)?;
-        write_pathbuf(&mut table_writer, &node.path)?;
+        node.write_record(&mut table_writer)?;
+    }
+    for index in table.removed.iter() {
+        table_writer.write_all(&index.to_le_bytes())?;
+        table_writer.write_all(&REMOVED_ENTRY.to_le_bytes())?;
     }
 
-    write_encrypted(writer, &table.key, table_writer.into_inner().as_ref())?;
-
-    writer.write_all(&current_position.to_le_bytes())?;
-   
+    let mut encrypted = Vec::new();
+    write_encrypted(&mut encrypted, &table.key, &plaintext)?;
+    writer.write_all(&encrypted)?;
 
-//    println!("Done writing {:?}", writer.stream_position()?);
+    Trailer {
+        table_offset: current_position,
+        table_length: encrypted.len() as u64,
+        entry_count: table.map.len().try_into()?,
+        archive_id: table.header.archive_id
+    }.write(writer, &table.key, &table.header)?;
     Ok(())
 }
 
 
 
-fn read_file_table<T: Read + Seek>(reader: &mut T, password: &str) -> Result<FileTable> {
+fn read_file_table<T: Read + Seek>(reader: &mut T, credentials: &Credentials) -> Result<FileTable> {
+    let header = ArchiveHeader::read(reader)?;
+    if header.is_legacy() {
+        return read_legacy_file_table(reader, credentials, header);
+    }
 
-   // reader.seek(SeekFrom::Start(0))?;
-    
-//    let mut salt = vec![0u8; SALT_LENGTH_IN_BYTES];
-   // let salt = &mut [0u8; SALT_LENGTH_IN_BYTES];
-   // reader.read_exact(salt)?;
+    let key = header.unlock(credentials)?;
+    let trailer = Trailer::read(reader, &key, &header)?;
 
+    let mut encrypted = vec![0u8; trailer.table_length.try_into()?];
+    reader.seek(SeekFrom::Start(trailer.table_offset))?;
+    reader.read_exact(&mut encrypted)?;
 
-    reader.seek(SeekFrom::End(-8))?;
+    // Decrypt the file table.
+    let decrypted = Zeroizing::new(read_encrypted(&mut Cursor::new(encrypted), &key)
+        .map_err(|_| ArchiveError::Authentication)?);
 
-    //    let stop_position = reader.stream_position()?;
+    let file_table = parse_file_table(&decrypted, key, header)?;
+    if file_table.map.len() != trailer.entry_count as usize {
+        return Err(anyhow!("The file table holds {} entries but the trailer records {}.", file_table.map.len(), trailer.entry_count));
+    }
+    Ok(file_table)
+}
 
+/// Reads the table of an archive predating the header, which ends with
+/// an unauthenticated `u64` pointing at the table.
+fn read_legacy_file_table<T: Read + Seek>(reader: &mut T, credentials: &Credentials, header: ArchiveHeader) -> Result<FileTable> {
+    let header_end = reader.stream_position()?;
 
+    let file_end = reader.seek(SeekFrom::End(0))?;
+    if file_end < header_end + 8 {
+        return Err(anyhow!("Not a sonors archive: the file is too short to contain a file table."));
+    }
 
+    reader.seek(SeekFrom::End(-8))?;
     let table_position = read_u64(reader)?;
-    //println!("Table position: {table_position}");
+    if table_position < header_end || table_position >= file_end - 8 {
+        return Err(anyhow!("Not a sonors archive: the file table position {table_position} lies outside of the file."));
+    }
     reader.seek(SeekFrom::Start(table_position))?;
 
-    let mut salt = vec![0u8; SALT_LENGTH_IN_BYTES];
-    reader.read_exact(&mut salt)?;
-
-    let key = create_key(&salt, password.as_bytes())?;
+    let key = header.unlock(credentials)?;
 
     // Decrypt the file table.
-    let decrypted = read_encrypted(reader, &key)?;
+    let decrypted = Zeroizing::new(read_encrypted(reader, &key)
+        .map_err(|_| ArchiveError::Authentication)
+        .context("Not a sonors archive or the password is incorrect.")?);
 
+    parse_file_table(&decrypted, key, header)
+}
 
-    let decrypted_len = decrypted.len();
-    let mut reader = Cursor::new(Vec::from(decrypted));
-    let mut file_table = FileTable::new(key, &salt);
+fn parse_file_table(decrypted: &[u8], key: SecretKey, header: ArchiveHeader) -> Result<FileTable> {
+    let decrypted_len = decrypted.len() as u64;
+    let mut reader = Cursor::new(decrypted);
+    let mut file_table = FileTable::new(key, header);
 
-    loop {
+    while reader.stream_position()? < decrypted_len {
         let key = read_u32(&mut reader)?;
         let value = read_u64(&mut reader)?;
-        let is_leaf = read_bool(&mut reader)?;
-        let path = read_pathbuf(&mut reader)?;
-       
- 
-        file_table.map.push((key, value, ArchivalNode {
-            path,
-            is_leaf
-        }));
-
-
-        if reader.stream_position()? == decrypted_len as u64 {
-            break
+        if value == REMOVED_ENTRY && !file_table.header.is_legacy() {
+            file_table.removed.push(key);
+            continue;
         }
+        let node = if file_table.header.is_legacy() {
+            ArchivalNode {
+                kind: if read_bool(&mut reader)? { NodeKind::File } else { NodeKind::Directory },
+                path: read_pathbuf(&mut reader)?,
+                ..Default::default()
+            }
+        } else {
+            ArchivalNode::read_record(&mut reader)?
+        };
+
+        file_table.map.push((key, value, node));
     }
     Ok(file_table)
 }
@@ -144,11 +199,11 @@ fn read_file_table<T: Read + Seek>(reader: &mut T, password: &str) -> Result<Fil
 
 #[cfg(test)]
 mod tests {
-    use std::{io::Cursor, path::Path};
+    use std::{collections::HashSet, fs::File, io::{BufReader, Cursor, Seek, SeekFrom}, path::Path};
 
     use anyhow::Result;
 
-    use crate::security::secure::{create_key, generate_salt};
+    use crate::{security::{credentials::Credentials, secure::generate_key, slots::KeySlot}, structure::header::ArchiveHeader, testing::test_params};
 
     use super::FileTable;
 
@@ -161,27 +216,60 @@ mod tests {
         let password = "default_password";
 
 
-        let salt = generate_salt();
-        let key = create_key(&salt, password.as_bytes())?;
+        let key = generate_key();
+        let mut header = ArchiveHeader::new();
+        header.key_slots[0] = KeySlot::wrap_password(&key, &Credentials::password(password), &test_params(), &header.archive_id)?;
+        header.write(&mut export)?;
 
-        let mut file_table = FileTable::new(key, &salt);
-        file_table.add(0, 32, crate::structure::node::ArchivalNode { path: Path::new("hello").to_path_buf(), is_leaf: true });
+        let mut file_table = FileTable::new(key, header);
+        file_table.add(0, 32, crate::structure::node::ArchivalNode { path: Path::new("hello").to_path_buf(), ..Default::default() });
 
         file_table.write(&mut export)?;
 
 
 
         let mut export = Cursor::new(export.into_inner());
-        let file_table = FileTable::from_reader(&mut export, password)?;
+        let file_table = FileTable::from_reader(&mut export, &Credentials::password(password))?;
 
         let first_entry = file_table.map.first().unwrap();
         assert_eq!(first_entry.0, 0);
         assert_eq!(first_entry.1, 32);
         assert_eq!(first_entry.2.path.to_str().unwrap(), "hello");
-        assert_eq!(first_entry.2.is_leaf, true);
+        assert!(first_entry.2.is_file());
 
+        // Removed entries keep their index.
+        let mut file_table = file_table;
+        file_table.add(1, 64, crate::structure::node::ArchivalNode { path: Path::new("world").to_path_buf(), ..Default::default() });
+        file_table.remove(&HashSet::from([0]));
+        assert_eq!(file_table.next_index()?, 2);
+
+        let mut export = Cursor::new(export.into_inner());
+        export.seek(SeekFrom::End(0))?;
+        file_table.write(&mut export)?;
+        export.rewind()?;
+        let file_table = FileTable::from_reader(&mut export, &Credentials::password(password))?;
+        assert_eq!(file_table.map.len(), 1);
+        assert_eq!(file_table.map[0].2.path, Path::new("world"));
+        assert_eq!(file_table.removed, vec![0]);
 
         Ok(())
     }
-}
 
+    #[test]
+    pub fn test_rejects_foreign_file() {
+        let mut export = Cursor::new(vec![0x42u8; 256]);
+        assert!(FileTable::from_reader(&mut export, &Credentials::password("default_password")).is_err());
+    }
+
+    #[test]
+    pub fn test_legacy_archive() -> Result<()> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("archive.srs");
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let file_table = FileTable::from_reader(&mut reader, &Credentials::password("hello"))?;
+        assert!(file_table.header().is_legacy());
+        assert!(file_table.map.iter().any(|(_, _, node)| node.path == Path::new("test/README.md")));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{anyhow, Result};
+
+use crate::{constants::ARCHIVE_ID_LENGTH_IN_BYTES, error::ArchiveError, ioutils::{read_u32, read_u64, write_u32, write_u64}, security::secure::{compute_mac, verify_mac}};
+
+use super::{header::ArchiveHeader, recovery::data_end};
+
+/// The magic bytes closing every versioned archive.
+pub const TRAILER_MAGIC: [u8; 8] = *b"SNRSTAIL";
+
+/// The size of the trailer in bytes.
+pub const TRAILER_LENGTH: u64 = 8 + 8 + 4 + ARCHIVE_ID_LENGTH_IN_BYTES as u64 + 32 + TRAILER_MAGIC.len() as u64;
+
+/// The purpose string the trailer MAC key is derived with.
+const TRAILER_MAC_PURPOSE: &str = "sonors 2024-08 archive trailer mac";
+
+/// The authenticated footer closing every versioned archive, locating
+/// the file table.
+///
+/// Laid out as:
+///
+/// [ u64 table offset ] [ u64 table length ] [ u32 entry count ] [ 16 bytes of archive id ] [ 32 bytes of mac ] [ 8 bytes of magic ]
+///
+/// The MAC covers the archive header, apart from its key slots, followed
+/// by every field before it, so neither the header nor the table location
+/// can be altered without the key.
+///
+/// Nothing counts the tables an archive had, and every append leaves the
+/// trailer before it in place. An archive cut back to the end of one of
+/// them is authenticated as well, with the table it had back then.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trailer {
+    pub table_offset: u64,
+    pub table_length: u64,
+    pub entry_count: u32,
+    pub archive_id: [u8; ARCHIVE_ID_LENGTH_IN_BYTES]
+}
+
+impl Trailer {
+    /// The fields stored in front of the MAC.
+    fn fields(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        write_u64(&mut bytes, self.table_offset)?;
+        write_u64(&mut bytes, self.table_length)?;
+        write_u32(&mut bytes, self.entry_count)?;
+        bytes.extend_from_slice(&self.archive_id);
+        Ok(bytes)
+    }
+    /// The bytes covered by the MAC, the authenticated part of the header
+    /// followed by the fields.
+    fn authenticated_bytes(&self, header: &ArchiveHeader) -> Result<Vec<u8>> {
+        let mut bytes = header.authenticated_bytes()?;
+        bytes.extend_from_slice(&self.fields()?);
+        Ok(bytes)
+    }
+    /// Writes the trailer, authenticating it and `header` with `key`.
+    pub fn write<W: Write>(&self, writer: &mut W, key: &[u8], header: &ArchiveHeader) -> Result<()> {
+        writer.write_all(&self.fields()?)?;
+        writer.write_all(&compute_mac(key, TRAILER_MAC_PURPOSE, &self.authenticated_bytes(header)?))?;
+        writer.write_all(&TRAILER_MAGIC)?;
+        Ok(())
+    }
+    /// Reads the trailer at the end of the archive, or in front of its
+    /// recovery record, and checks it against the header and the key
+    /// before any of its offsets are trusted.
+    ///
+    /// What an interrupted append left after the archive is skipped, see
+    /// [archive_end](super::recovery::archive_end).
+    pub fn read<R: Read + Seek>(reader: &mut R, key: &[u8], header: &ArchiveHeader) -> Result<Self> {
+        let file_end = data_end(reader)?;
+        if file_end < TRAILER_LENGTH {
+            return Err(anyhow!("The archive is truncated: it is too short to contain a trailer."));
+        }
+        reader.seek(SeekFrom::Start(file_end - TRAILER_LENGTH))?;
+
+        let trailer = Self {
+            table_offset: read_u64(reader)?,
+            table_length: read_u64(reader)?,
+            entry_count: read_u32(reader)?,
+            archive_id: {
+                let mut archive_id = [0u8; ARCHIVE_ID_LENGTH_IN_BYTES];
+                reader.read_exact(&mut archive_id)?;
+                archive_id
+            }
+        };
+        let mut mac = [0u8; 32];
+        reader.read_exact(&mut mac)?;
+        let mut magic = [0u8; TRAILER_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+
+        if magic != TRAILER_MAGIC {
+            return Err(anyhow!("The archive is truncated or has data appended: no trailer found at its end."));
+        }
+        if !verify_mac(key, TRAILER_MAC_PURPOSE, &trailer.authenticated_bytes(header)?, &mac) {
+            Err(ArchiveError::Authentication)?
+        }
+        if trailer.archive_id != header.archive_id {
+            return Err(anyhow!("The archive trailer belongs to a different archive."));
+        }
+        if trailer.table_offset.checked_add(trailer.table_length) != Some(file_end - TRAILER_LENGTH) {
+            return Err(anyhow!("The archive trailer does not point at a file table directly before it."));
+        }
+        Ok(trailer)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use anyhow::Result;
+    use chacha20poly1305::{aead::OsRng, ChaCha20Poly1305, KeyInit};
+
+    use crate::structure::header::ArchiveHeader;
+
+    use super::{Trailer, TRAILER_LENGTH};
+
+    fn sample() -> Result<(Vec<u8>, Vec<u8>, ArchiveHeader)> {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+        let header = ArchiveHeader::new();
+
+        let mut export = Vec::new();
+        header.write(&mut export)?;
+        let table_offset = export.len() as u64;
+        export.write_all(&[0xAA; 10])?;
+
+        Trailer {
+            table_offset,
+            table_length: 10,
+            entry_count: 1,
+            archive_id: header.archive_id
+        }.write(&mut export, &key, &header)?;
+        assert_eq!(export.len() as u64, table_offset + 10 + TRAILER_LENGTH);
+
+        Ok((export, key, header))
+    }
+
+    #[test]
+    pub fn test_trailer_roundtrip() -> Result<()> {
+        let (export, key, header) = sample()?;
+        let trailer = Trailer::read(&mut Cursor::new(export), &key, &header)?;
+        assert_eq!(trailer.table_length, 10);
+        assert_eq!(trailer.entry_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_trailer_detects_tampering() -> Result<()> {
+        let (export, key, header) = sample()?;
+
+        // Rewritten table offset.
+        let mut tampered = export.clone();
+        let start = tampered.len() - TRAILER_LENGTH as usize;
+        tampered[start] ^= 0x01;
+        assert!(Trailer::read(&mut Cursor::new(tampered), &key, &header).is_err());
+
+        // Appended data is skipped, as an interrupted append leaves it.
+        let mut appended = export.clone();
+        appended.extend_from_slice(&[0u8; 4]);
+        assert_eq!(Trailer::read(&mut Cursor::new(appended), &key, &header)?.table_length, 10);
+
+        // Truncated archive.
+        let truncated = export[..export.len() - 1].to_vec();
+        assert!(Trailer::read(&mut Cursor::new(truncated), &key, &header).is_err());
+
+        // Altered header.
+        let mut altered = header.clone();
+        altered.flags ^= 0x01;
+        assert!(Trailer::read(&mut Cursor::new(export), &key, &altered).is_err());
+
+        Ok(())
+    }
+}
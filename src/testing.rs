@@ -0,0 +1,56 @@
+use std::{fs::{create_dir_all, write}, io::Cursor};
+
+use anyhow::Result;
+use tempfile::TempDir;
+
+use crate::{archive::{ArchiveWriter, CreateOptions}, security::{credentials::Credentials, kdf::KdfParams, secret::SecretKey, secure::generate_key, slots::KeySlot}, structure::header::ArchiveHeader};
+
+/// The password every test archive is created with.
+pub const TEST_PASSWORD: &str = "password";
+
+/// Key derivation cheap enough for tests.
+pub fn test_params() -> KdfParams {
+    KdfParams::new(1024, 1, 1).expect("valid parameters")
+}
+
+/// Options creating archives with [test_params].
+pub fn test_options() -> CreateOptions {
+    CreateOptions {
+        kdf_params: test_params(),
+        ..Default::default()
+    }
+}
+
+/// A temporary directory holding `files`, their parents created as
+/// needed.
+pub fn test_tree(files: &[(&str, &[u8])]) -> Result<TempDir> {
+    let directory = tempfile::tempdir()?;
+    for (path, contents) in files {
+        let path = directory.path().join(path);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        write(path, contents)?;
+    }
+    Ok(directory)
+}
+
+/// An archive in memory holding `files`, each added under its own name,
+/// locked with [TEST_PASSWORD].
+pub fn test_archive(files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+    let source = test_tree(files)?;
+    let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), TEST_PASSWORD, test_options())?;
+    for (path, _) in files {
+        writer.add_path(source.path().join(path))?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// A new archive key and a header with it in the first slot, locked with
+/// [TEST_PASSWORD], for tests writing the parts of an archive by hand.
+pub fn test_header() -> Result<(SecretKey, ArchiveHeader)> {
+    let key = generate_key();
+    let mut header = ArchiveHeader::new();
+    header.key_slots[0] = KeySlot::wrap_password(&key, &Credentials::password(TEST_PASSWORD), &test_params(), &header.archive_id)?;
+    Ok((key, header))
+}
@@ -0,0 +1,62 @@
+use std::{fs::{create_dir, read, write}, path::Path, process::{Command, Output}};
+
+use anyhow::Result;
+
+/// Runs the binary in `directory` unlocking archives with `password`, with
+/// key derivation parameters cheap enough for tests.
+fn sonors(directory: &Path, password: &str, args: &[&str]) -> Result<Output> {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sonors"));
+    command.current_dir(directory).env("SONORS_PASSWORD", password).args(args);
+    if args.first() == Some(&"create") {
+        command.args(["--m-cost", "1024", "--t-cost", "1"]);
+    }
+    Ok(command.output()?)
+}
+
+#[test]
+pub fn test_create_list_extract_verify() -> Result<()> {
+    let directory = tempfile::tempdir()?;
+    create_dir(directory.path().join("input"))?;
+    write(directory.path().join("input/file"), b"contents")?;
+
+    let created = sonors(directory.path(), "password", &["create", "archive.srs", "input"])?;
+    assert!(created.status.success(), "{}", String::from_utf8_lossy(&created.stderr));
+
+    let listed = sonors(directory.path(), "password", &["list", "archive.srs"])?;
+    assert!(listed.status.success());
+    assert_eq!(String::from_utf8(listed.stdout)?.lines().collect::<Vec<_>>(), ["input", "input/file"]);
+
+    let extracted = sonors(directory.path(), "password", &["extract", "archive.srs", "-C", "output"])?;
+    assert!(extracted.status.success());
+    assert_eq!(read(directory.path().join("output/input/file"))?, b"contents");
+
+    let verified = sonors(directory.path(), "password", &["verify", "archive.srs"])?;
+    assert!(verified.status.success());
+    assert!(String::from_utf8(verified.stdout)?.ends_with("archive.srs: OK\n"));
+    Ok(())
+}
+
+#[test]
+pub fn test_exit_codes() -> Result<()> {
+    let directory = tempfile::tempdir()?;
+    let mut contents = vec![0u8; 20_000];
+    blake3::Hasher::new().finalize_xof().fill(&mut contents);
+    write(directory.path().join("file"), &contents)?;
+    assert!(sonors(directory.path(), "password", &["create", "archive.srs", "file"])?.status.success());
+
+    // 3 when the password opens no key slot.
+    let wrong = sonors(directory.path(), "wrong", &["list", "archive.srs"])?;
+    assert_eq!(wrong.status.code(), Some(3));
+
+    // 4 when a chunk fails to authenticate.
+    let mut archive = read(directory.path().join("archive.srs"))?;
+    archive[10_000] ^= 0x01;
+    write(directory.path().join("archive.srs"), archive)?;
+    let corrupt = sonors(directory.path(), "password", &["verify", "archive.srs"])?;
+    assert_eq!(corrupt.status.code(), Some(4));
+
+    // 1 for any other failure.
+    let missing = sonors(directory.path(), "password", &["list", "missing.srs"])?;
+    assert_eq!(missing.status.code(), Some(1));
+    Ok(())
+}